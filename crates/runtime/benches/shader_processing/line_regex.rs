@@ -0,0 +1,206 @@
+//! A private copy of the line-by-line regex pipeline that the directive tree replaced, reduced to
+//! what the benchmark shaders use: `#ifdef`/`#ifndef`/`#elifdef`/`#elifndef`/`#else`/`#endif`,
+//! `#define`/`#undef`, `#{NAME}` substitutions and plain (not namespaced) `#import` lines. Every
+//! line is still matched against the same regexes, in the same order, as the old processor did.
+
+use std::collections::{HashMap, HashSet};
+
+use regex::Regex;
+use runtime::ShaderDefVal;
+
+type ShaderDefs = HashMap<String, ShaderDefVal>;
+
+/// A directive line outside of what this pipeline supports, like `#if` or `#import "path"`.
+#[derive(Debug)]
+pub struct UnsupportedDirective(pub String);
+
+pub struct LineRegexProcessor {
+    if_regex: Regex,
+    ifdef_regex: Regex,
+    ifndef_regex: Regex,
+    elif_regex: Regex,
+    elifdef_regex: Regex,
+    elifndef_regex: Regex,
+    else_regex: Regex,
+    endif_regex: Regex,
+    define_regex: Regex,
+    undef_regex: Regex,
+    import_asset_path_regex: Regex,
+    import_custom_path_regex: Regex,
+    allow_multiple_imports_regex: Regex,
+    define_import_path_regex: Regex,
+    def_substitution_regex: Regex,
+}
+
+impl Default for LineRegexProcessor {
+    fn default() -> Self {
+        Self {
+            if_regex: Regex::new(r"^\s*#\s*if\b(.*)").unwrap(),
+            ifdef_regex: Regex::new(r"^\s*#\s*ifdef\s*([\w|\d|_]+)").unwrap(),
+            ifndef_regex: Regex::new(r"^\s*#\s*ifndef\s*([\w|\d|_]+)").unwrap(),
+            elif_regex: Regex::new(r"^\s*#\s*elif\b(.*)").unwrap(),
+            elifdef_regex: Regex::new(r"^\s*#\s*elifdef\s*([\w|\d|_]+)").unwrap(),
+            elifndef_regex: Regex::new(r"^\s*#\s*elifndef\s*([\w|\d|_]+)").unwrap(),
+            else_regex: Regex::new(r"^\s*#\s*else").unwrap(),
+            endif_regex: Regex::new(r"^\s*#\s*endif").unwrap(),
+            define_regex: Regex::new(r"^\s*#\s*define\s+(\w+)(.*)").unwrap(),
+            undef_regex: Regex::new(r"^\s*#\s*undef\s+(\w+)").unwrap(),
+            import_asset_path_regex: Regex::new(r#"^\s*#\s*import\s*"(.+)""#).unwrap(),
+            import_custom_path_regex: Regex::new(r"^\s*#\s*import\s*(.+)").unwrap(),
+            allow_multiple_imports_regex: Regex::new(r"^\s*#\s*allow_multiple_imports\s*$")
+                .unwrap(),
+            define_import_path_regex: Regex::new(r"^\s*#\s*define_import_path\s+(\S+)").unwrap(),
+            def_substitution_regex: Regex::new(r"#\{(\w+)\}").unwrap(),
+        }
+    }
+}
+
+impl LineRegexProcessor {
+    /// Processes `source`, pasting in the sources of its imports from `modules` by import path.
+    pub fn process(
+        &self,
+        source: &str,
+        shader_defs: &[ShaderDefVal],
+        modules: &HashMap<String, String>,
+    ) -> Result<String, UnsupportedDirective> {
+        let mut shader_defs = shader_defs
+            .iter()
+            .map(|def| (def.name().to_string(), def.clone()))
+            .collect();
+        let mut emitted_imports = HashSet::new();
+        self.process_with_defs(source, &mut shader_defs, modules, &mut emitted_imports)
+    }
+
+    fn process_with_defs(
+        &self,
+        source: &str,
+        shader_defs: &mut ShaderDefs,
+        modules: &HashMap<String, String>,
+        emitted_imports: &mut HashSet<String>,
+    ) -> Result<String, UnsupportedDirective> {
+        let mut scopes = vec![Scope::root()];
+        let mut output = String::new();
+        for line in source.lines() {
+            if self.process_conditional(line, &mut scopes, shader_defs)?
+                || !scopes.last().unwrap().active
+            {
+                continue;
+            }
+
+            if self.allow_multiple_imports_regex.is_match(line)
+                || self.define_import_path_regex.is_match(line)
+            {
+                // Only affects how this shader is imported.
+            } else if self.import_asset_path_regex.is_match(line) {
+                return Err(UnsupportedDirective(line.to_string()));
+            } else if let Some(cap) = self.import_custom_path_regex.captures(line) {
+                let import = cap.get(1).unwrap().as_str().trim();
+                if emitted_imports.insert(import.to_string()) {
+                    let mut import_defs = shader_defs.clone();
+                    output.push_str(&self.process_with_defs(
+                        &modules[import],
+                        &mut import_defs,
+                        modules,
+                        emitted_imports,
+                    )?);
+                }
+            } else {
+                self.substitute_defs(line, shader_defs, &mut output);
+                output.push('\n');
+            }
+        }
+        assert_eq!(scopes.len(), 1, "missing #endif");
+        Ok(output)
+    }
+
+    fn substitute_defs(&self, line: &str, shader_defs: &ShaderDefs, output: &mut String) {
+        let mut last_end = 0;
+        for cap in self.def_substitution_regex.captures_iter(line) {
+            let whole = cap.get(0).unwrap();
+            let name = cap.get(1).unwrap().as_str();
+            output.push_str(&line[last_end..whole.start()]);
+            output.push_str(&shader_defs[name].value_as_string());
+            last_end = whole.end();
+        }
+        output.push_str(&line[last_end..]);
+    }
+
+    /// Applies `line` to `scopes` and `shader_defs` if it is a conditional or def directive.
+    /// Returns `false` if the line is not one of those directives.
+    fn process_conditional(
+        &self,
+        line: &str,
+        scopes: &mut Vec<Scope>,
+        shader_defs: &mut ShaderDefs,
+    ) -> Result<bool, UnsupportedDirective> {
+        let is_defined =
+            |name: &str, defs: &ShaderDefs| defs.get(name).is_some_and(|def| def.is_defined());
+        if self.if_regex.is_match(line) || self.elif_regex.is_match(line) {
+            return Err(UnsupportedDirective(line.to_string()));
+        } else if let Some(cap) = self.ifdef_regex.captures(line) {
+            let parent_active = scopes.last().unwrap().active;
+            let condition = is_defined(cap.get(1).unwrap().as_str(), shader_defs);
+            scopes.push(Scope::new(parent_active, condition));
+        } else if let Some(cap) = self.ifndef_regex.captures(line) {
+            let parent_active = scopes.last().unwrap().active;
+            let condition = !is_defined(cap.get(1).unwrap().as_str(), shader_defs);
+            scopes.push(Scope::new(parent_active, condition));
+        } else if let Some(cap) = self.elifdef_regex.captures(line) {
+            let condition = is_defined(cap.get(1).unwrap().as_str(), shader_defs);
+            scopes.last_mut().unwrap().enter_branch(condition);
+        } else if let Some(cap) = self.elifndef_regex.captures(line) {
+            let condition = !is_defined(cap.get(1).unwrap().as_str(), shader_defs);
+            scopes.last_mut().unwrap().enter_branch(condition);
+        } else if self.else_regex.is_match(line) {
+            scopes.last_mut().unwrap().enter_branch(true);
+        } else if self.endif_regex.is_match(line) {
+            scopes.pop();
+            assert!(!scopes.is_empty(), "too many #endifs");
+        } else if let Some(cap) = self.define_regex.captures(line) {
+            if scopes.last().unwrap().active {
+                let name = cap.get(1).unwrap().as_str();
+                let value = cap.get(2).unwrap().as_str().trim();
+                let def = if value.is_empty() {
+                    ShaderDefVal::from(name)
+                } else {
+                    format!("{}={}", name, value).parse().unwrap()
+                };
+                shader_defs.insert(name.to_string(), def);
+            }
+        } else if let Some(cap) = self.undef_regex.captures(line) {
+            if scopes.last().unwrap().active {
+                shader_defs.remove(cap.get(1).unwrap().as_str());
+            }
+        } else {
+            return Ok(false);
+        }
+        Ok(true)
+    }
+}
+
+/// The state of a single `#ifdef`/`#ifndef` block.
+struct Scope {
+    parent_active: bool,
+    active: bool,
+    branch_taken: bool,
+}
+
+impl Scope {
+    fn root() -> Self {
+        Self::new(true, true)
+    }
+
+    fn new(parent_active: bool, condition: bool) -> Self {
+        let active = parent_active && condition;
+        Self {
+            parent_active,
+            active,
+            branch_taken: active,
+        }
+    }
+
+    fn enter_branch(&mut self, condition: bool) {
+        self.active = self.parent_active && !self.branch_taken && condition;
+        self.branch_taken |= self.active;
+    }
+}
@@ -0,0 +1,103 @@
+//! Compares processing shaders that are parsed into a directive tree once, when they're created,
+//! with the line-by-line regex pipeline that tokenized them again for every `process` call.
+
+mod line_regex;
+
+use std::collections::HashMap;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use line_regex::LineRegexProcessor;
+use runtime::{Shader, ShaderDefVal, ShaderHandle, ShaderImport, ShaderProcessor};
+
+const MODULES: usize = 16;
+const FUNCTIONS_PER_MODULE: usize = 64;
+
+/// A module with plain functions, conditional blocks and def substitutions.
+fn module_source(module: usize) -> String {
+    let mut source = String::new();
+    for function in 0..FUNCTIONS_PER_MODULE {
+        source.push_str(&format!(
+            "fn m{module}_f{function}(x: f32) -> f32 {{\n\
+             #ifdef FAST_PATH\n    return x * 2.0;\n\
+             #else\n    let scale = f32(#{{SCALE}});\n    return x * scale;\n\
+             #endif\n}}\n\n"
+        ));
+    }
+    source
+}
+
+fn main_source() -> String {
+    let mut source = String::new();
+    for module in 0..MODULES {
+        source.push_str(&format!("#import bench_module_{module}\n"));
+    }
+    source.push_str("fn main_fn() -> f32 {\n    return 1.0;\n}\n");
+    source
+}
+
+/// Creates the main shader and all its imports from source.
+fn parse_shaders(
+    main: &str,
+    modules: &[String],
+) -> (
+    Shader,
+    HashMap<ShaderHandle, Shader>,
+    HashMap<ShaderImport, ShaderHandle>,
+) {
+    let mut shaders = HashMap::new();
+    let mut import_handles = HashMap::new();
+    for (module, source) in modules.iter().enumerate() {
+        let handle = ShaderHandle::new();
+        let shader =
+            Shader::from_wgsl(source.clone()).with_import_path(format!("bench_module_{module}"));
+        import_handles.insert(shader.import_path().unwrap().clone(), handle);
+        shaders.insert(handle, shader);
+    }
+    (Shader::from_wgsl(main.to_string()), shaders, import_handles)
+}
+
+fn process(c: &mut Criterion) {
+    let processor = ShaderProcessor::default();
+    let main = main_source();
+    let modules = (0..MODULES).map(module_source).collect::<Vec<_>>();
+    let shader_defs = [ShaderDefVal::Int("SCALE".into(), 3)];
+
+    let (shader, shaders, import_handles) = parse_shaders(&main, &modules);
+    let line_regex = LineRegexProcessor::default();
+    let module_sources = modules
+        .iter()
+        .enumerate()
+        .map(|(module, source)| (format!("bench_module_{module}"), source.clone()))
+        .collect::<HashMap<_, _>>();
+    assert_eq!(
+        processor
+            .process(&shader, &shader_defs, &shaders, &import_handles)
+            .unwrap()
+            .get_wgsl_source(),
+        Some(
+            line_regex
+                .process(&main, &shader_defs, &module_sources)
+                .unwrap_or_else(|err| panic!("unsupported directive: {}", err.0))
+                .as_str()
+        ),
+    );
+
+    c.bench_function("process_tree", |b| {
+        b.iter(|| {
+            processor
+                .process(black_box(&shader), &shader_defs, &shaders, &import_handles)
+                .unwrap()
+        })
+    });
+
+    c.bench_function("process_line_by_line", |b| {
+        b.iter(|| {
+            line_regex
+                .process(black_box(&main), &shader_defs, &module_sources)
+                .unwrap()
+        })
+    });
+}
+
+criterion_group!(benches, process);
+criterion_main!(benches);
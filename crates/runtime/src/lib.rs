@@ -1,6 +1,7 @@
 mod setup;
-mod shader;
+pub mod shader;
 pub struct Application;
+pub use shader::{Shader, ShaderDefVal, ShaderHandle, ShaderImport, ShaderProcessor};
 pub use winit::*;
 
 pub trait EventHandler {
@@ -2,14 +2,47 @@ use naga::back::wgsl::WriterFlags;
 use naga::{valid::ModuleInfo, Module};
 use once_cell::sync::Lazy;
 use regex::Regex;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::atomic::{AtomicU32, Ordering};
-use std::{
-    borrow::Cow, collections::HashSet, marker::Copy, ops::Deref, path::PathBuf, str::FromStr,
-};
+use std::sync::Arc;
+use std::{borrow::Cow, marker::Copy, ops::Deref, ops::Range, path::PathBuf, str::FromStr};
 use wgpu::util::make_spirv;
 
 use thiserror::Error;
+use tree::{Segment, ShaderTree};
+
+mod asset_path;
+mod backend;
+mod expr;
+mod hot_reload;
+mod layout;
+mod library;
+mod loader;
+mod naga_module;
+mod namespace;
+mod permutations;
+mod policy;
+mod precompiled;
+mod provider;
+mod tree;
+mod validation;
+
+pub use backend::{GlslOptions, HlslOptions, MslOptions};
+pub use hot_reload::{ShaderHotReloader, ShaderReload, ShaderWatchError};
+pub use layout::{BindGroupLayoutError, BindGroupLayouts};
+pub use library::{ShaderCacheStats, ShaderLibrary, ShaderLibraryError};
+pub use loader::{ShaderLoadError, ShaderLoader};
+pub use naga_module::NagaModule;
+pub use permutations::{
+    PermutationConstraint, PermutationError, PermutationExplorer, PermutationFailure,
+    PermutationReport, PermutationResult, ReferencedDef,
+};
+pub use policy::ImportPolicy;
+pub use precompiled::{
+    PrecompileError, PrecompiledShader, PrecompiledShaderBuilder, PrecompiledShaderError,
+};
+pub use provider::{ChainedShaderProvider, EmbeddedShaderProvider, ShaderSourceProvider};
+pub use validation::{capabilities_from_features, ValidationConfig};
 
 #[derive(Debug, Error)]
 pub enum ShaderReflectError {
@@ -30,35 +63,96 @@ pub struct Shader {
     source: Source,
     import_path: Option<ShaderImport>,
     imports: Vec<ShaderImport>,
+    allow_multiple_imports: bool,
+    declared_import_path: Option<String>,
+    asset_path: Option<String>,
+    tree: Result<Arc<ShaderTree>, ProcessShaderError>,
 }
 
 impl Shader {
     pub fn from_wgsl(source: impl Into<Cow<'static, str>>) -> Shader {
         let source = source.into();
-        Shader {
-            imports: SHADER_IMPORT_PROCESSOR.get_imports_from_str(&source),
-            source: Source::Wgsl(source),
-            import_path: None,
-        }
+        let tree = ShaderTree::parse(&source);
+        Self::from_tree(Source::Wgsl(source), tree)
     }
 
+    /// `#define` and `#undef` lines are left for naga's GLSL frontend, since GLSL macros can have
+    /// any value. They still change the shader defs seen by the `#if*` directives that follow.
     pub fn from_glsl(source: impl Into<Cow<'static, str>>, stage: naga::ShaderStage) -> Shader {
         let source = source.into();
+        let tree = ShaderTree::parse_glsl(&source);
+        Self::from_tree(Source::Glsl(source, stage), tree)
+    }
+
+    /// A shader whose imports and import directives are read from its parsed `tree`. If the tree
+    /// failed to parse, they're read line by line instead, so that the shader is still importable
+    /// under its declared path and its importers report its parse error.
+    fn from_tree(source: Source, tree: Result<ShaderTree, ProcessShaderError>) -> Shader {
+        let (imports, allow_multiple_imports, declared_import_path) = match (&tree, &source) {
+            (Ok(tree), _) => (
+                tree.imports()
+                    .into_iter()
+                    .map(|directive| directive.import.clone())
+                    .collect(),
+                tree.allows_multiple_imports(),
+                tree.declared_import_path().map(str::to_string),
+            ),
+            (Err(_), Source::Wgsl(source) | Source::Glsl(source, _)) => (
+                SHADER_IMPORT_PROCESSOR.get_imports_from_str(source),
+                SHADER_IMPORT_PROCESSOR.allows_multiple_imports(source),
+                SHADER_IMPORT_PROCESSOR.get_import_path_from_str(source),
+            ),
+            (Err(_), _) => (Vec::new(), false, None),
+        };
         Shader {
-            imports: SHADER_IMPORT_PROCESSOR.get_imports_from_str(&source),
-            source: Source::Glsl(source, stage),
-            import_path: None,
+            imports,
+            allow_multiple_imports,
+            import_path: declared_import_path.clone().map(ShaderImport::Custom),
+            declared_import_path,
+            asset_path: None,
+            tree: tree.map(Arc::new),
+            source,
         }
     }
 
     pub fn from_spirv(source: impl Into<Cow<'static, [u8]>>) -> Shader {
         Shader {
             imports: Vec::new(),
+            allow_multiple_imports: false,
+            declared_import_path: None,
+            asset_path: None,
+            tree: Ok(Arc::default()),
             source: Source::SpirV(source.into()),
             import_path: None,
         }
     }
 
+    /// A shader from naga IR, which can't contain preprocessor directives.
+    pub fn from_naga_module(naga_module: NagaModule) -> Shader {
+        Shader {
+            imports: Vec::new(),
+            allow_multiple_imports: false,
+            declared_import_path: None,
+            asset_path: None,
+            tree: Ok(Arc::default()),
+            source: Source::NagaModule(naga_module),
+            import_path: None,
+        }
+    }
+
+    /// A shader that can only be processed with the def combinations it was precompiled for.
+    pub fn from_precompiled_spirv(precompiled: PrecompiledShader) -> Shader {
+        Shader {
+            imports: Vec::new(),
+            allow_multiple_imports: false,
+            declared_import_path: None,
+            asset_path: None,
+            tree: Ok(Arc::default()),
+            source: Source::PrecompiledSpirVMacros(Arc::new(precompiled)),
+            import_path: None,
+        }
+    }
+
     pub fn set_import_path<P: Into<String>>(&mut self, import_path: P) {
         self.import_path = Some(ShaderImport::Custom(import_path.into()));
     }
@@ -73,9 +167,64 @@ impl Shader {
         self.import_path.as_ref()
     }
 
+    /// Sets the `/`-separated path of the file this shader was loaded from, relative to its root
+    /// directory. `#import "path"` directives in the shader are resolved relative to it, and a
+    /// [`ShaderLibrary`] makes the shader importable as `#import "<asset_path>"`.
+    pub fn set_asset_path<P: AsRef<str>>(&mut self, asset_path: P) {
+        self.asset_path = Some(asset_path::normalize(asset_path.as_ref()));
+    }
+
+    pub fn with_asset_path<P: AsRef<str>>(mut self, asset_path: P) -> Self {
+        self.set_asset_path(asset_path);
+        self
+    }
+
+    #[inline]
+    pub fn asset_path(&self) -> Option<&str> {
+        self.asset_path.as_deref()
+    }
+
+    /// The import path declared by the shader's own `#define_import_path` directive, if any.
+    #[inline]
+    pub fn declared_import_path(&self) -> Option<&str> {
+        self.declared_import_path.as_deref()
+    }
+
+    /// Checks that an import path set with [`set_import_path`](Self::set_import_path) agrees with
+    /// the one declared by `#define_import_path`.
+    pub fn validate_import_path(&self) -> Result<(), ProcessShaderError> {
+        match (&self.declared_import_path, &self.import_path) {
+            (Some(declared), Some(import_path))
+                if *import_path != ShaderImport::Custom(declared.clone()) =>
+            {
+                Err(ProcessShaderError::ConflictingImportPath {
+                    declared: declared.clone(),
+                    set: import_path.clone(),
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
     pub fn imports(&self) -> impl ExactSizeIterator<Item = &ShaderImport> {
         self.imports.iter()
     }
+
+    /// The imports that processing this shader with `shader_defs` would expand. Unlike
+    /// [`imports`](Self::imports), this skips imports in inactive `#if*` blocks.
+    pub fn imports_with_defs(
+        &self,
+        shader_defs: &[ShaderDefVal],
+    ) -> Result<Vec<ShaderImport>, ProcessShaderError> {
+        SHADER_IMPORT_PROCESSOR.get_imports_with_defs(self, shader_defs)
+    }
+
+    /// Whether the shader contains an `#allow_multiple_imports` directive. By default a shader
+    /// imported more than once while processing is only expanded the first time.
+    #[inline]
+    pub fn allows_multiple_imports(&self) -> bool {
+        self.allow_multiple_imports
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -83,17 +232,19 @@ pub enum Source {
     Wgsl(Cow<'static, str>),
     Glsl(Cow<'static, str>, naga::ShaderStage),
     SpirV(Cow<'static, [u8]>),
-    // TODO: consider the following
-    // PrecompiledSpirVMacros(HashMap<HashSet<String>, Vec<u32>>)
-    // NagaModule(Module) ... Module impls Serialize/Deserialize
+    /// SPIR-V blobs for a fixed set of def combinations, see [`PrecompiledShader`].
+    PrecompiledSpirVMacros(Arc<PrecompiledShader>),
+    /// Naga IR that was parsed (and possibly validated) before, see [`NagaModule`].
+    NagaModule(NagaModule),
 }
 
 /// A processed [Shader]. This cannot contain preprocessor directions. It must be "ready to compile"
-#[derive(PartialEq, Eq, Debug)]
+#[derive(PartialEq, Eq, Hash, Debug, Clone)]
 pub enum ProcessedShader {
     Wgsl(Cow<'static, str>),
     Glsl(Cow<'static, str>, naga::ShaderStage),
     SpirV(Cow<'static, [u8]>),
+    NagaModule(NagaModule),
 }
 
 impl ProcessedShader {
@@ -113,7 +264,34 @@ impl ProcessedShader {
     }
 
     pub fn reflect(&self) -> Result<ShaderReflection, ShaderReflectError> {
+        self.reflect_with_validation(&ValidationConfig::default())
+    }
+
+    /// Like [`reflect`](Self::reflect), validating with `validation`. A [`NagaModule`] that
+    /// already has its [`ModuleInfo`] isn't validated again, unless `validation` is stricter than
+    /// the config the info came from.
+    pub fn reflect_with_validation(
+        &self,
+        validation: &ValidationConfig,
+    ) -> Result<ShaderReflection, ShaderReflectError> {
         let module = match &self {
+            ProcessedShader::NagaModule(naga_module) => {
+                let module = naga_module.module().clone();
+                return Ok(match naga_module.info() {
+                    Some(module_info) if naga_module.validation().implies(validation) => {
+                        ShaderReflection {
+                            module,
+                            module_info: module_info.clone(),
+                            validation: *naga_module.validation(),
+                        }
+                    }
+                    _ => ShaderReflection {
+                        module_info: Arc::new(validation.validator().validate(&module)?),
+                        module,
+                        validation: *validation,
+                    },
+                });
+            }
             // TODO: process macros here
             ProcessedShader::Wgsl(source) => naga::front::wgsl::parse_str(source)?,
             ProcessedShader::Glsl(source, shader_stage) => {
@@ -130,21 +308,27 @@ impl ProcessedShader {
                 },
             )?,
         };
-        let module_info = naga::valid::Validator::new(
-            naga::valid::ValidationFlags::default(),
-            naga::valid::Capabilities::default(),
-        )
-        .validate(&module)?;
+        let module_info = validation.validator().validate(&module)?;
 
         Ok(ShaderReflection {
-            module,
-            module_info,
+            module: Arc::new(module),
+            module_info: Arc::new(module_info),
+            validation: *validation,
         })
     }
 
     pub fn get_module_descriptor(
         &self,
-    ) -> Result<wgpu::ShaderModuleDescriptor, AsModuleDescriptorError> {
+    ) -> Result<wgpu::ShaderModuleDescriptor<'_>, AsModuleDescriptorError> {
+        self.get_module_descriptor_with_options(&ModuleDescriptorOptions::default())
+    }
+
+    /// Like [`get_module_descriptor`](Self::get_module_descriptor), converting and validating
+    /// shaders as set in `options`.
+    pub fn get_module_descriptor_with_options(
+        &self,
+        options: &ModuleDescriptorOptions,
+    ) -> Result<wgpu::ShaderModuleDescriptor<'_>, AsModuleDescriptorError> {
         Ok(wgpu::ShaderModuleDescriptor {
             label: None,
             source: match self {
@@ -153,16 +337,29 @@ impl ProcessedShader {
                     // This isn't neccessary, but catches errors early during hot reloading of invalid wgsl shaders.
                     // Eventually, wgpu will have features that will make this unneccessary like compilation info
                     // or error scopes, but until then parsing the shader twice during development the easiest solution.
-                    let _ = self.reflect()?;
+                    let _ = self.reflect_with_validation(&options.validation)?;
 
                     wgpu::ShaderSource::Wgsl(source.clone())
                 }
-                ProcessedShader::Glsl(_source, _stage) => {
-                    let reflection = self.reflect()?;
-                    // TODO: it probably makes more sense to convert this to spirv, but as of writing
-                    // this comment, naga's spirv conversion is broken
-                    let wgsl = reflection.get_wgsl()?;
-                    wgpu::ShaderSource::Wgsl(wgsl.into())
+                ProcessedShader::Glsl(_, _) | ProcessedShader::NagaModule(_) => {
+                    let reflection = self.reflect_with_validation(&options.validation)?;
+                    let spirv = match options.format {
+                        ConvertedShaderFormat::SpirV => match reflection.get_spirv() {
+                            Ok(spirv) => Some(spirv),
+                            Err(err) => {
+                                tracing::warn!(
+                                    "Failed to convert shader to SPIR-V, falling back to WGSL: {}",
+                                    err
+                                );
+                                None
+                            }
+                        },
+                        ConvertedShaderFormat::Wgsl => None,
+                    };
+                    match spirv {
+                        Some(spirv) => wgpu::ShaderSource::SpirV(spirv.into()),
+                        None => wgpu::ShaderSource::Wgsl(reflection.get_wgsl()?.into()),
+                    }
                 }
                 ProcessedShader::SpirV(source) => make_spirv(source),
             },
@@ -170,6 +367,24 @@ impl ProcessedShader {
     }
 }
 
+/// Options for [`ProcessedShader::get_module_descriptor_with_options`].
+#[derive(Debug, Clone, Default)]
+pub struct ModuleDescriptorOptions {
+    pub format: ConvertedShaderFormat,
+    pub validation: ValidationConfig,
+}
+
+/// What [`ProcessedShader::get_module_descriptor`] converts shaders to that wgpu can't take as
+/// they are, like GLSL and naga IR.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum ConvertedShaderFormat {
+    /// SPIR-V, falling back to WGSL if naga can't write the module as SPIR-V.
+    #[default]
+    SpirV,
+    /// WGSL. Converting GLSL to WGSL loses precision qualifiers and some builtins.
+    Wgsl,
+}
+
 #[derive(Error, Debug)]
 pub enum AsModuleDescriptorError {
     #[error(transparent)]
@@ -178,11 +393,35 @@ pub enum AsModuleDescriptorError {
     WgslConversion(#[from] naga::back::wgsl::Error),
     #[error(transparent)]
     SpirVConversion(#[from] naga::back::spv::Error),
+    #[error(transparent)]
+    HlslConversion(#[from] naga::back::hlsl::Error),
+    #[error(transparent)]
+    MslConversion(#[from] naga::back::msl::Error),
+    #[error(transparent)]
+    GlslConversion(#[from] naga::back::glsl::Error),
+    #[error("Failed to convert the entry point '{entry_point}' to HLSL: {source}")]
+    HlslEntryPoint {
+        entry_point: String,
+        #[source]
+        source: naga::back::hlsl::EntryPointError,
+    },
+    #[error("Failed to convert the entry point '{entry_point}' to MSL: {source}")]
+    MslEntryPoint {
+        entry_point: String,
+        #[source]
+        source: naga::back::msl::EntryPointError,
+    },
+    #[error("The shader has no entry point named '{0}'.")]
+    UnknownEntryPoint(String),
+    #[error("The shader has more than one entry point, so one has to be selected.")]
+    EntryPointRequired,
 }
 
 pub struct ShaderReflection {
-    pub module: Module,
-    pub module_info: ModuleInfo,
+    pub module: Arc<Module>,
+    pub module_info: Arc<ModuleInfo>,
+    /// The config the module was validated with to get its `module_info`.
+    pub validation: ValidationConfig,
 }
 
 impl ShaderReflection {
@@ -203,7 +442,7 @@ impl ShaderReflection {
     }
 }
 
-#[derive(Error, Debug, PartialEq, Eq)]
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
 pub enum ProcessShaderError {
     #[error("Too many '# endif' lines. Each endif should be preceded by an if statement.")]
     TooManyEndIfs,
@@ -211,19 +450,96 @@ pub enum ProcessShaderError {
         "Not enough '# endif' lines. Each if statement should be followed by an endif statement."
     )]
     NotEnoughEndIfs,
+    #[error("'#if' blocks are nested deeper than the maximum of {0}.")]
+    ConditionalsTooDeep(usize),
+    #[error("Found '#else' without a matching '#if', '#ifdef' or '#ifndef'.")]
+    ElseWithoutIf,
+    #[error("Found '#{0}' without a matching '#if', '#ifdef' or '#ifndef'.")]
+    ElifWithoutIf(String),
+    #[error("Found '#{0}' after '#else'. The '#else' branch must be the last one in its block.")]
+    ElifAfterElse(String),
+    #[error("Found a second '#else' in the same block.")]
+    ElseAfterElse,
+    #[error("Unexpected token '{token}' in '#if {expression}'.")]
+    UnexpectedIfToken { expression: String, token: String },
+    #[error("Unexpected end of expression in '#if {0}'.")]
+    UnexpectedEndOfIfExpression(String),
+    #[error("Invalid integer literal '{literal}' in '#if {expression}'.")]
+    InvalidIfLiteral { expression: String, literal: String },
+    #[error("'#if {expression}' nests parentheses and unary operators deeper than the maximum of {max_depth}.")]
+    IfExpressionTooDeep {
+        expression: String,
+        max_depth: usize,
+    },
+    #[error("Substituted shader def '#{{{0}}}' is not defined.")]
+    UndefinedSubstitution(String),
+    #[error(
+        "Invalid shader def '{0}'. Expected 'NAME' or 'NAME=VALUE' with a bool, i32 or u32 value."
+    )]
+    InvalidShaderDef(String),
     #[error("This Shader's format does not support processing shader defs.")]
     ShaderFormatDoesNotSupportShaderDefs,
     #[error("This Shader's formatdoes not support imports.")]
     ShaderFormatDoesNotSupportImports,
     #[error("Unresolved import: {0:?}.")]
     UnresolvedImport(ShaderImport),
+    #[error("The shader declares '#define_import_path {declared}', but its import path was set to {set:?}.")]
+    ConflictingImportPath { declared: String, set: ShaderImport },
+    #[error("Import cycle detected: {}.", format_import_chain(.0))]
+    ImportCycle(Vec<ShaderImport>),
+    #[error("Imports are nested deeper than the maximum of {max_depth}: {}.", format_import_chain(.imports))]
+    ImportTooDeep {
+        max_depth: usize,
+        imports: Vec<ShaderImport>,
+    },
+    #[error(
+        "The shader import {0:?} can't have a 'with' clause. Only namespaced imports like 'lib::shadows' or aliased ones can."
+    )]
+    ImportDefsNotNamespaced(ShaderImport),
+    #[error("'{name}' refers to items of two different imports, the second being {import:?}. Give one of them an alias with 'as'.")]
+    AmbiguousImportName { name: String, import: ShaderImport },
+    #[error("The shader import {import:?} does not declare an item named '{item}'.")]
+    UnknownImportItem { import: ShaderImport, item: String },
+    #[error(
+        "The import path '{0}' is absolute. Only paths relative to the shader roots are allowed."
+    )]
+    AbsoluteImportPath(String),
+    #[error("The import path '{0}' leads outside of the shader roots.")]
+    ImportEscapesRoot(String),
+    #[error("The shader asset '{asset_path}' is a symlink to {target:?}, outside of its root.")]
+    SymlinkEscapesRoot { asset_path: String, target: PathBuf },
+    #[error("The expanded shader is {size} bytes, more than the maximum of {max_size}.")]
+    ExpandedSizeTooLarge { size: usize, max_size: usize },
+    #[error("Processing the shader expands more than the maximum of {max_count} imports.")]
+    TooManyImports { max_count: usize },
+    #[error("Failed to load the shader import {import:?}: {message}")]
+    ImportLoadFailed {
+        import: ShaderImport,
+        message: String,
+    },
+    #[error("The shader was not precompiled for the shader defs {0:?}.")]
+    PermutationNotPrecompiled(Vec<ShaderDefVal>),
     #[error("The shader import {0:?} does not match the source file type. Support for this might be added in the future.")]
     MismatchedImportFormat(ShaderImport),
 }
 
+fn format_import_chain(imports: &[ShaderImport]) -> String {
+    imports
+        .iter()
+        .map(|import| match import {
+            ShaderImport::AssetPath(path) => format!("\"{}\"", path),
+            ShaderImport::Custom(path) => path.clone(),
+        })
+        .collect::<Vec<_>>()
+        .join(" -> ")
+}
+
 pub struct ShaderImportProcessor {
     import_asset_path_regex: Regex,
     import_custom_path_regex: Regex,
+    allow_multiple_imports_regex: Regex,
+    define_import_path_regex: Regex,
+    conditionals: ConditionalProcessor,
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Hash)]
@@ -232,37 +548,286 @@ pub enum ShaderImport {
     Custom(String),
 }
 
+impl ShaderImport {
+    /// Custom import paths made of `::`-separated segments, like `lib::lighting`, are namespaced
+    /// modules. Their items are mangled when they're imported, and referenced from the importing
+    /// shader as `lighting::item` (or `alias::item`, see [`ImportDirective::alias`]),
+    /// `lib::lighting::item` or, when listed in the import, `item`. An import that is both aliased
+    /// and has a `with` clause is only referenced through its alias and listed items. Two imports
+    /// can't make the same name refer to different items.
+    /// An aliased custom import is namespaced too, see [`ImportDirective::namespace`].
+    /// All other imports are pasted into the importing shader as they are.
+    pub fn namespace(&self) -> Option<&str> {
+        match self {
+            ShaderImport::Custom(path) if path.contains("::") => Some(path),
+            _ => None,
+        }
+    }
+}
+
+/// A parsed `#import` line.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ImportDirective {
+    pub import: ShaderImport,
+    /// The items of a namespaced module listed as `#import lib::lighting::{pbr, Light}`, which can
+    /// be referenced without a module prefix.
+    pub items: Vec<String>,
+    /// The name given to a module with `#import lib::noise as n`, used as `n::item` instead of
+    /// `noise::item`. Aliasing a custom import makes it namespaced, so `#import noise_v1 as n1`
+    /// and `#import noise_v2 as n2` can be used side by side.
+    pub alias: Option<String>,
+    /// Def changes that only apply while expanding this import, as in
+    /// `#import lib::shadows with SOFT_SHADOWS, TAPS=8, !PCF`. Only namespaced imports (see
+    /// [`ImportDirective::namespace`]) can have them.
+    pub shader_defs: Vec<ImportShaderDef>,
+}
+
+impl ImportDirective {
+    /// The module path the imported items are namespaced under, if any: the import's own
+    /// [`ShaderImport::namespace`], or the whole path of an aliased custom import.
+    pub fn namespace(&self) -> Option<&str> {
+        match (&self.import, &self.alias) {
+            (ShaderImport::Custom(path), Some(_)) => Some(path),
+            (import, _) => import.namespace(),
+        }
+    }
+
+    pub fn instance(&self) -> ImportInstance {
+        ImportInstance::new(
+            self.import.clone(),
+            self.namespace().is_some(),
+            &self.shader_defs,
+        )
+    }
+}
+
+/// A def change in the `with` clause of an `#import`.
+#[derive(Debug, PartialEq, Eq, Clone, Hash)]
+pub enum ImportShaderDef {
+    /// Adds or overrides a def, written as `NAME` or `NAME=VALUE`.
+    Set(ShaderDefVal),
+    /// Removes a def, written as `!NAME`.
+    Remove(String),
+}
+
+impl ImportShaderDef {
+    pub fn name(&self) -> &str {
+        match self {
+            ImportShaderDef::Set(def) => def.name(),
+            ImportShaderDef::Remove(name) => name,
+        }
+    }
+
+    fn apply(&self, shader_defs: &mut ShaderDefs) {
+        match self {
+            ImportShaderDef::Set(def) => {
+                shader_defs.insert(def.name().to_string(), def.clone());
+            }
+            ImportShaderDef::Remove(name) => {
+                shader_defs.remove(name);
+            }
+        }
+    }
+}
+
+impl FromStr for ImportShaderDef {
+    type Err = ProcessShaderError;
+
+    fn from_str(def: &str) -> Result<Self, Self::Err> {
+        match def.trim().strip_prefix('!') {
+            Some(name) => match name.parse()? {
+                ShaderDefVal::Bool(name, true) => Ok(ImportShaderDef::Remove(name)),
+                _ => Err(ProcessShaderError::InvalidShaderDef(def.to_string())),
+            },
+            None => def.parse().map(ImportShaderDef::Set),
+        }
+    }
+}
+
+/// An import expanded with a specific set of def changes. Each distinct instance is expanded (and
+/// deduplicated) separately, and the items of a namespaced module are mangled per instance.
+#[derive(Debug, PartialEq, Eq, Clone, Hash)]
+pub struct ImportInstance {
+    pub import: ShaderImport,
+    /// Whether the items are mangled, see [`ImportDirective::namespace`].
+    pub namespaced: bool,
+    /// The def changes, sorted by name with only the last change per name kept.
+    pub shader_defs: Vec<ImportShaderDef>,
+}
+
+impl ImportInstance {
+    pub fn new(import: ShaderImport, namespaced: bool, shader_defs: &[ImportShaderDef]) -> Self {
+        let mut normalized = Vec::<ImportShaderDef>::with_capacity(shader_defs.len());
+        for def in shader_defs.iter().rev() {
+            if !normalized.iter().any(|other| other.name() == def.name()) {
+                normalized.push(def.clone());
+            }
+        }
+        normalized.sort_by(|a, b| a.name().cmp(b.name()));
+        Self {
+            import,
+            namespaced,
+            shader_defs: normalized,
+        }
+    }
+
+    /// The name `item` of a namespaced import is emitted with.
+    fn mangle(&self, item: &str) -> Option<String> {
+        match &self.import {
+            ShaderImport::Custom(path) if self.namespaced => {
+                Some(namespace::mangle(path, &self.shader_defs, item))
+            }
+            _ => None,
+        }
+    }
+}
+
 impl Default for ShaderImportProcessor {
     fn default() -> Self {
         Self {
             import_asset_path_regex: Regex::new(r#"^\s*#\s*import\s*"(.+)""#).unwrap(),
             import_custom_path_regex: Regex::new(r"^\s*#\s*import\s*(.+)").unwrap(),
+            allow_multiple_imports_regex: Regex::new(r"^\s*#\s*allow_multiple_imports\s*$")
+                .unwrap(),
+            define_import_path_regex: Regex::new(r"^\s*#\s*define_import_path\s+(\S+)").unwrap(),
+            conditionals: ConditionalProcessor::default(),
         }
     }
 }
 
 impl ShaderImportProcessor {
+    /// Returns the path of the first `#define_import_path` directive in `shader`.
+    pub fn get_import_path_from_str(&self, shader: &str) -> Option<String> {
+        shader.lines().find_map(|line| {
+            self.define_import_path_regex
+                .captures(line)
+                .map(|cap| cap.get(1).unwrap().as_str().to_string())
+        })
+    }
+
     pub fn get_imports(&self, shader: &Shader) -> Vec<ShaderImport> {
+        shader.imports().cloned().collect()
+    }
+
+    pub fn allows_multiple_imports(&self, shader: &str) -> bool {
+        shader
+            .lines()
+            .any(|line| self.allow_multiple_imports_regex.is_match(line))
+    }
+
+    pub fn get_imports_from_str(&self, shader: &str) -> Vec<ShaderImport> {
+        shader
+            .lines()
+            .filter_map(|line| self.parse_import(line))
+            .collect()
+    }
+
+    /// Like [`get_imports_with_defs_from_str`](Self::get_imports_with_defs_from_str), for any
+    /// [`Shader`].
+    pub fn get_imports_with_defs(
+        &self,
+        shader: &Shader,
+        shader_defs: &[ShaderDefVal],
+    ) -> Result<Vec<ShaderImport>, ProcessShaderError> {
         match &shader.source {
-            Source::Wgsl(source) => self.get_imports_from_str(source),
-            Source::Glsl(source, _stage) => self.get_imports_from_str(source),
-            Source::SpirV(_source) => Vec::new(),
+            Source::Wgsl(source) | Source::Glsl(source, _) => Self::get_imports_from_tree(
+                shader.tree.as_ref().map_err(Clone::clone)?,
+                source,
+                shader_defs,
+            ),
+            Source::SpirV(_) | Source::PrecompiledSpirVMacros(_) | Source::NagaModule(_) => {
+                Ok(Vec::new())
+            }
         }
     }
 
-    pub fn get_imports_from_str(&self, shader: &str) -> Vec<ShaderImport> {
+    /// The imports in `shader` that processing it with `shader_defs` would expand.
+    pub fn get_imports_with_defs_from_str(
+        &self,
+        shader: &str,
+        shader_defs: &[ShaderDefVal],
+    ) -> Result<Vec<ShaderImport>, ProcessShaderError> {
+        Self::get_imports_from_tree(&ShaderTree::parse(shader)?, shader, shader_defs)
+    }
+
+    fn get_imports_from_tree(
+        tree: &ShaderTree,
+        source: &str,
+        shader_defs: &[ShaderDefVal],
+    ) -> Result<Vec<ShaderImport>, ProcessShaderError> {
+        let mut shader_defs = collect_shader_defs(shader_defs);
         let mut imports = Vec::new();
-        for line in shader.lines() {
-            if let Some(cap) = self.import_asset_path_regex.captures(line) {
-                let import = cap.get(1).unwrap();
-                imports.push(ShaderImport::AssetPath(import.as_str().to_string()));
-            } else if let Some(cap) = self.import_custom_path_regex.captures(line) {
-                let import = cap.get(1).unwrap();
-                imports.push(ShaderImport::Custom(import.as_str().to_string()));
+        tree.evaluate(source, &mut shader_defs, &mut |segment, _shader_defs| {
+            if let Segment::Import(directive) = segment {
+                imports.push(directive.import.clone());
             }
-        }
+            Ok(())
+        })?;
+        Ok(imports)
+    }
+
+    fn parse_import(&self, line: &str) -> Option<ShaderImport> {
+        self.parse_import_directive(line)
+            .ok()
+            .flatten()
+            .map(|directive| directive.import)
+    }
 
-        imports
+    pub fn parse_import_directive(
+        &self,
+        line: &str,
+    ) -> Result<Option<ImportDirective>, ProcessShaderError> {
+        if let Some(cap) = self.import_asset_path_regex.captures(line) {
+            let import = cap.get(1).unwrap();
+            Ok(Some(ImportDirective {
+                import: ShaderImport::AssetPath(import.as_str().to_string()),
+                items: Vec::new(),
+                alias: None,
+                shader_defs: Vec::new(),
+            }))
+        } else if let Some(cap) = self.import_custom_path_regex.captures(line) {
+            let import = cap.get(1).unwrap().as_str().trim();
+            // The `with` clause may come before or after the alias.
+            let (import, shader_defs, trailing_alias) = match import.split_once(" with ") {
+                Some((import, shader_defs)) => {
+                    let (shader_defs, alias) = match shader_defs.rsplit_once(" as ") {
+                        Some((shader_defs, alias)) => (shader_defs, Some(alias)),
+                        None => (shader_defs, None),
+                    };
+                    let shader_defs = shader_defs
+                        .split(',')
+                        .map(ImportShaderDef::from_str)
+                        .collect::<Result<_, _>>()?;
+                    (import.trim_end(), shader_defs, alias)
+                }
+                None => (import, Vec::new(), None),
+            };
+            let (import, alias) = match import.rsplit_once(" as ") {
+                Some((import, alias)) => (import.trim_end(), Some(alias)),
+                None => (import, trailing_alias),
+            };
+            let alias = alias.map(|alias| alias.trim().to_string());
+            let (path, items) = match import.strip_suffix('}').and_then(|i| i.split_once("::{")) {
+                Some((path, items)) => (
+                    path.trim(),
+                    items
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|item| !item.is_empty())
+                        .map(String::from)
+                        .collect(),
+                ),
+                None => (import, Vec::new()),
+            };
+            Ok(Some(ImportDirective {
+                import: ShaderImport::Custom(path.to_string()),
+                items,
+                alias,
+                shader_defs,
+            }))
+        } else {
+            Ok(None)
+        }
     }
 }
 
@@ -281,32 +846,243 @@ impl ShaderHandle {
     }
 }
 
-pub struct ShaderProcessor {
+impl Default for ShaderHandle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A shader def passed to [`ShaderProcessor::process`], optionally carrying a value that can be
+/// compared in `#if` expressions and substituted into the source with `#{NAME}`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ShaderDefVal {
+    Bool(String, bool),
+    Int(String, i32),
+    UInt(String, u32),
+}
+
+impl ShaderDefVal {
+    pub fn name(&self) -> &str {
+        match self {
+            ShaderDefVal::Bool(name, _)
+            | ShaderDefVal::Int(name, _)
+            | ShaderDefVal::UInt(name, _) => name,
+        }
+    }
+
+    /// A `Bool` def set to `false` behaves as if it wasn't defined in `#ifdef` and `#if` checks,
+    /// but can still be substituted.
+    pub fn is_defined(&self) -> bool {
+        !matches!(self, ShaderDefVal::Bool(_, false))
+    }
+
+    /// The value as it is substituted into the shader source.
+    pub fn value_as_string(&self) -> String {
+        match self {
+            ShaderDefVal::Bool(_, value) => value.to_string(),
+            ShaderDefVal::Int(_, value) => value.to_string(),
+            ShaderDefVal::UInt(_, value) => format!("{}u", value),
+        }
+    }
+
+    /// Parses a `true`/`false`, i32 or `u`-suffixed u32 value for the def `name`.
+    fn parse_value(name: &str, value: &str) -> Option<Self> {
+        let name = name.to_string();
+        match value {
+            "true" => Some(ShaderDefVal::Bool(name, true)),
+            "false" => Some(ShaderDefVal::Bool(name, false)),
+            _ => match value.strip_suffix('u') {
+                Some(value) => value
+                    .parse()
+                    .ok()
+                    .map(|value| ShaderDefVal::UInt(name, value)),
+                None => value
+                    .parse()
+                    .ok()
+                    .map(|value| ShaderDefVal::Int(name, value)),
+            },
+        }
+    }
+
+    fn value_as_i64(&self) -> i64 {
+        match self {
+            ShaderDefVal::Bool(_, value) => *value as i64,
+            ShaderDefVal::Int(_, value) => *value as i64,
+            ShaderDefVal::UInt(_, value) => *value as i64,
+        }
+    }
+}
+
+impl From<&str> for ShaderDefVal {
+    fn from(name: &str) -> Self {
+        ShaderDefVal::Bool(name.to_string(), true)
+    }
+}
+
+impl From<String> for ShaderDefVal {
+    fn from(name: String) -> Self {
+        ShaderDefVal::Bool(name, true)
+    }
+}
+
+/// Parses `NAME`, `NAME=true`, `NAME=-4` or `NAME=16u`.
+impl FromStr for ShaderDefVal {
+    type Err = ProcessShaderError;
+
+    fn from_str(def: &str) -> Result<Self, Self::Err> {
+        let invalid = || ProcessShaderError::InvalidShaderDef(def.to_string());
+        let (name, value) = match def.split_once('=') {
+            Some((name, value)) => (name.trim(), Some(value.trim())),
+            None => (def.trim(), None),
+        };
+        if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+            return Err(invalid());
+        }
+        match value {
+            None => Ok(ShaderDefVal::from(name)),
+            Some(value) => ShaderDefVal::parse_value(name, value).ok_or_else(invalid),
+        }
+    }
+}
+
+/// Handles the conditional (`#if`, `#ifdef`, `#ifndef`, `#elif`, `#elifdef`, `#elifndef`, `#else`,
+/// `#endif`) and, in WGSL, def (`#define`, `#undef`) directives of a shader, one line at a time.
+struct ConditionalProcessor {
+    if_regex: Regex,
     ifdef_regex: Regex,
     ifndef_regex: Regex,
+    elif_regex: Regex,
+    elifdef_regex: Regex,
+    elifndef_regex: Regex,
     else_regex: Regex,
     endif_regex: Regex,
+    define_regex: Regex,
+    undef_regex: Regex,
 }
 
-impl Default for ShaderProcessor {
+impl Default for ConditionalProcessor {
     fn default() -> Self {
         Self {
+            if_regex: Regex::new(r"^\s*#\s*if\b(.*)").unwrap(),
             ifdef_regex: Regex::new(r"^\s*#\s*ifdef\s*([\w|\d|_]+)").unwrap(),
             ifndef_regex: Regex::new(r"^\s*#\s*ifndef\s*([\w|\d|_]+)").unwrap(),
+            elif_regex: Regex::new(r"^\s*#\s*elif\b(.*)").unwrap(),
+            elifdef_regex: Regex::new(r"^\s*#\s*elifdef\s*([\w|\d|_]+)").unwrap(),
+            elifndef_regex: Regex::new(r"^\s*#\s*elifndef\s*([\w|\d|_]+)").unwrap(),
             else_regex: Regex::new(r"^\s*#\s*else").unwrap(),
             endif_regex: Regex::new(r"^\s*#\s*endif").unwrap(),
+            define_regex: Regex::new(r"^\s*#\s*define\s+(\w+)(.*)").unwrap(),
+            undef_regex: Regex::new(r"^\s*#\s*undef\s+(\w+)").unwrap(),
+        }
+    }
+}
+
+pub struct ShaderProcessor {
+    def_substitution_regex: Regex,
+    leak_import_defines: bool,
+    max_import_depth: usize,
+    policy: ImportPolicy,
+}
+
+impl Default for ShaderProcessor {
+    fn default() -> Self {
+        Self {
+            def_substitution_regex: Regex::new(r"#\{(\w+)\}").unwrap(),
+            leak_import_defines: false,
+            max_import_depth: ShaderProcessor::DEFAULT_MAX_IMPORT_DEPTH,
+            policy: ImportPolicy::default(),
         }
     }
 }
 
 impl ShaderProcessor {
+    pub const DEFAULT_MAX_IMPORT_DEPTH: usize = 32;
+
+    /// Sets how deeply imports may be nested before processing fails with
+    /// [`ProcessShaderError::ImportTooDeep`].
+    pub fn set_max_import_depth(&mut self, max_import_depth: usize) {
+        self.max_import_depth = max_import_depth;
+    }
+
+    pub fn with_max_import_depth(mut self, max_import_depth: usize) -> Self {
+        self.set_max_import_depth(max_import_depth);
+        self
+    }
+
+    /// Sets whether `#define` and `#undef` directives in an imported shader stay visible to the
+    /// importing shader after the `#import` line. By default they only apply to the imported shader
+    /// (and its own imports). Imports with a `with` clause never leak their defines.
+    pub fn set_leak_import_defines(&mut self, leak_import_defines: bool) {
+        self.leak_import_defines = leak_import_defines;
+    }
+
+    pub fn with_leak_import_defines(mut self, leak_import_defines: bool) -> Self {
+        self.set_leak_import_defines(leak_import_defines);
+        self
+    }
+
+    /// Sets the policy that `#import "path"` directives and the expanded output are checked
+    /// against.
+    pub fn set_policy(&mut self, policy: ImportPolicy) {
+        self.policy = policy;
+    }
+
+    pub fn with_policy(mut self, policy: ImportPolicy) -> Self {
+        self.set_policy(policy);
+        self
+    }
+
+    #[inline]
+    pub fn policy(&self) -> &ImportPolicy {
+        &self.policy
+    }
+
     pub fn process(
         &self,
         shader: &Shader,
-        shader_defs: &[String],
+        shader_defs: &[ShaderDefVal],
         shaders: &HashMap<ShaderHandle, Shader>,
         import_handles: &HashMap<ShaderImport, ShaderHandle>,
     ) -> Result<ProcessedShader, ProcessShaderError> {
+        let provider = provider::HandleMaps {
+            shaders,
+            import_handles,
+        };
+        self.process_with_provider(shader, shader_defs, &provider)
+    }
+
+    /// Processes `shader`, resolving its imports lazily through `provider`.
+    pub fn process_with_provider(
+        &self,
+        shader: &Shader,
+        shader_defs: &[ShaderDefVal],
+        provider: &dyn ShaderSourceProvider,
+    ) -> Result<ProcessedShader, ProcessShaderError> {
+        let mut shader_defs = collect_shader_defs(shader_defs);
+        let mut context = ProcessContext {
+            provider,
+            import_stack: ImportStack::new(shader.import_path().cloned().or_else(|| {
+                shader
+                    .asset_path()
+                    .map(|path| ShaderImport::AssetPath(path.to_string()))
+            })),
+            emitted_imports: HashSet::new(),
+            module_items: HashMap::new(),
+            import_count: 0,
+        };
+        self.process_with_defs(shader, None, &mut shader_defs, &mut context)
+    }
+
+    /// Processes `shader` with a mutable def set, so that `#define` and `#undef` directives can
+    /// update it for the rest of the file and for the imports that follow them.
+    fn process_with_defs(
+        &self,
+        shader: &Shader,
+        instance: Option<&ImportInstance>,
+        shader_defs: &mut ShaderDefs,
+        context: &mut ProcessContext,
+    ) -> Result<ProcessedShader, ProcessShaderError> {
+        shader.validate_import_path()?;
         let shader_str = match &shader.source {
             Source::Wgsl(source) => source.deref(),
             Source::Glsl(source, _stage) => source.deref(),
@@ -317,114 +1093,860 @@ impl ShaderProcessor {
                     return Err(ProcessShaderError::ShaderFormatDoesNotSupportShaderDefs);
                 }
             }
+            Source::NagaModule(naga_module) => {
+                if shader_defs.is_empty() {
+                    return Ok(ProcessedShader::NagaModule(naga_module.clone()));
+                } else {
+                    return Err(ProcessShaderError::ShaderFormatDoesNotSupportShaderDefs);
+                }
+            }
+            Source::PrecompiledSpirVMacros(precompiled) => {
+                let shader_defs = shader_defs.values().cloned().collect::<Vec<_>>();
+                return match precompiled.get(&shader_defs) {
+                    Some(spirv) => Ok(ProcessedShader::SpirV(
+                        spirv.iter().flat_map(|word| word.to_le_bytes()).collect(),
+                    )),
+                    None => Err(ProcessShaderError::PermutationNotPrecompiled(
+                        precompiled.permutation_defs(&shader_defs),
+                    )),
+                };
+            }
         };
 
-        let shader_defs_unique = HashSet::<String>::from_iter(shader_defs.iter().cloned());
-        let mut scopes = vec![true];
-        let mut final_string = String::new();
-        for line in shader_str.lines() {
-            if let Some(cap) = self.ifdef_regex.captures(line) {
-                let def = cap.get(1).unwrap();
-                scopes.push(*scopes.last().unwrap() && shader_defs_unique.contains(def.as_str()));
-            } else if let Some(cap) = self.ifndef_regex.captures(line) {
-                let def = cap.get(1).unwrap();
-                scopes.push(*scopes.last().unwrap() && !shader_defs_unique.contains(def.as_str()));
-            } else if self.else_regex.is_match(line) {
-                let mut is_parent_scope_truthy = true;
-                if scopes.len() > 1 {
-                    is_parent_scope_truthy = scopes[scopes.len() - 2];
+        let tree = shader.tree.as_ref().map_err(Clone::clone)?;
+        let mut output = ShaderOutput::default();
+        tree.evaluate(shader_str, shader_defs, &mut |segment, shader_defs| {
+            match segment {
+                Segment::Text(text) => output.text.push_str(text),
+                Segment::Substitute(line) => {
+                    self.substitute_defs(line, shader_defs, &mut output.text)?
                 }
-                if let Some(last) = scopes.last_mut() {
-                    *last = is_parent_scope_truthy && !*last;
+                Segment::Import(directive) => {
+                    self.apply_import(directive, shader, shader_defs, context, &mut output)?;
+                    // Checked as the output grows, so that the cap also bounds memory use.
+                    return self.policy.check_expanded_size(output.text.len());
                 }
-            } else if self.endif_regex.is_match(line) {
-                scopes.pop();
-                if scopes.is_empty() {
-                    return Err(ProcessShaderError::TooManyEndIfs);
-                }
-            } else if let Some(cap) = SHADER_IMPORT_PROCESSOR
-                .import_asset_path_regex
-                .captures(line)
-            {
-                let import = ShaderImport::AssetPath(cap.get(1).unwrap().as_str().to_string());
-                self.apply_import(
-                    import_handles,
-                    shaders,
-                    &import,
-                    shader,
-                    shader_defs,
-                    &mut final_string,
-                )?;
-            } else if let Some(cap) = SHADER_IMPORT_PROCESSOR
-                .import_custom_path_regex
-                .captures(line)
-            {
-                let import = ShaderImport::Custom(cap.get(1).unwrap().as_str().to_string());
-                self.apply_import(
-                    import_handles,
-                    shaders,
-                    &import,
-                    shader,
-                    shader_defs,
-                    &mut final_string,
-                )?;
-            } else if *scopes.last().unwrap() {
-                final_string.push_str(line);
-                final_string.push('\n');
             }
-        }
+            output.text.push('\n');
+            self.policy.check_expanded_size(output.text.len())
+        })?;
 
-        if scopes.len() != 1 {
-            return Err(ProcessShaderError::NotEnoughEndIfs);
+        // A namespaced module's own items are mangled, so they can't clash with the items of the
+        // importing shader or other modules.
+        if let Some(instance) = instance.filter(|instance| instance.namespaced) {
+            let items = namespace::declared_items(&output.own_text());
+            for item in &items {
+                if let Some(mangled) = instance.mangle(item) {
+                    output.names.insert(item.clone(), mangled);
+                }
+            }
+            context.module_items.insert(instance.clone(), items);
         }
 
-        let processed_source = Cow::from(final_string);
+        let processed_source = Cow::from(output.finish());
+        self.policy.check_expanded_size(processed_source.len())?;
 
         match &shader.source {
             Source::Wgsl(_source) => Ok(ProcessedShader::Wgsl(processed_source)),
             Source::Glsl(_source, stage) => Ok(ProcessedShader::Glsl(processed_source, *stage)),
-            Source::SpirV(_source) => {
+            Source::SpirV(_) | Source::PrecompiledSpirVMacros(_) | Source::NagaModule(_) => {
                 unreachable!("SpirV has early return");
             }
         }
     }
 
+    /// Appends `line` to `final_string`, replacing every `#{NAME}` with the value of the `NAME` def.
+    fn substitute_defs(
+        &self,
+        line: &str,
+        shader_defs: &ShaderDefs,
+        final_string: &mut String,
+    ) -> Result<(), ProcessShaderError> {
+        let mut last_end = 0;
+        for cap in self.def_substitution_regex.captures_iter(line) {
+            let whole = cap.get(0).unwrap();
+            let name = cap.get(1).unwrap().as_str();
+            let def = shader_defs
+                .get(name)
+                .ok_or_else(|| ProcessShaderError::UndefinedSubstitution(name.to_string()))?;
+            final_string.push_str(&line[last_end..whole.start()]);
+            final_string.push_str(&def.value_as_string());
+            last_end = whole.end();
+        }
+        final_string.push_str(&line[last_end..]);
+        Ok(())
+    }
+
     fn apply_import(
         &self,
-        import_handles: &HashMap<ShaderImport, ShaderHandle>,
-        shaders: &HashMap<ShaderHandle, Shader>,
-        import: &ShaderImport,
+        directive: &ImportDirective,
         shader: &Shader,
-        shader_defs: &[String],
-        final_string: &mut String,
+        shader_defs: &mut ShaderDefs,
+        context: &mut ProcessContext,
+        output: &mut ShaderOutput,
     ) -> Result<(), ProcessShaderError> {
-        let imported_shader = import_handles
-            .get(import)
-            .and_then(|handle| shaders.get(handle))
-            .ok_or_else(|| ProcessShaderError::UnresolvedImport(import.clone()))?;
-        let imported_processed =
-            self.process(imported_shader, shader_defs, shaders, import_handles)?;
+        let import = &directive.import;
+        let namespaced = directive.namespace().is_some();
+        // The items of a plain import aren't mangled, so two differently configured copies of it
+        // would redefine each other's items.
+        if !directive.shader_defs.is_empty() && !namespaced {
+            return Err(ProcessShaderError::ImportDefsNotNamespaced(import.clone()));
+        }
+        let (import, imported_shader) =
+            resolve_import(context.provider, import, shader, &self.policy)?
+                .ok_or_else(|| ProcessShaderError::UnresolvedImport(import.clone()))?;
+        let instance = ImportInstance::new(import.clone(), namespaced, &directive.shader_defs);
+        context.import_stack.push(&import, self.max_import_depth)?;
+        // Like `#pragma once`: each import instance is only expanded the first time it's
+        // encountered.
+        if imported_shader.allows_multiple_imports()
+            || context.emitted_imports.insert(instance.clone())
+        {
+            context.import_count += 1;
+            if let Err(err) = self.policy.check_import_count(context.import_count) {
+                context.import_stack.pop();
+                return Err(err);
+            }
+            let imported_processed = if self.leak_import_defines && instance.shader_defs.is_empty()
+            {
+                self.process_with_defs(&imported_shader, Some(&instance), shader_defs, context)
+            } else {
+                let mut import_defs = shader_defs.clone();
+                for def in &instance.shader_defs {
+                    def.apply(&mut import_defs);
+                }
+                self.process_with_defs(&imported_shader, Some(&instance), &mut import_defs, context)
+            };
+            context.import_stack.pop();
+            let imported_processed = imported_processed?;
 
-        match &shader.source {
-            Source::Wgsl(_) => {
-                if let ProcessedShader::Wgsl(import_source) = &imported_processed {
-                    final_string.push_str(import_source);
-                } else {
-                    return Err(ProcessShaderError::MismatchedImportFormat(import.clone()));
+            let import_source = match (&shader.source, &imported_processed) {
+                (Source::Wgsl(_), ProcessedShader::Wgsl(import_source))
+                | (Source::Glsl(_, _), ProcessedShader::Glsl(import_source, _)) => import_source,
+                (
+                    Source::SpirV(_) | Source::PrecompiledSpirVMacros(_) | Source::NagaModule(_),
+                    _,
+                ) => {
+                    return Err(ProcessShaderError::ShaderFormatDoesNotSupportImports);
+                }
+                _ => return Err(ProcessShaderError::MismatchedImportFormat(import.clone())),
+            };
+            output.push_import(import_source);
+        } else {
+            context.import_stack.pop();
+        }
+
+        if let (Some(namespace), true) = (directive.namespace(), instance.namespaced) {
+            let items = &context.module_items[&instance];
+            let short_name = match &directive.alias {
+                Some(alias) => alias,
+                None => namespace.rsplit("::").next().unwrap(),
+            };
+            // An aliased import with a `with` clause is only referenced through its alias, so that
+            // it can be told apart from other instances of the same module.
+            let claims_full_path = directive.alias.is_none() || instance.shader_defs.is_empty();
+            for item in items {
+                let mangled = instance.mangle(item).unwrap();
+                if claims_full_path {
+                    output.claim_name(format!("{}::{}", namespace, item), &mangled, &import)?;
                 }
+                output.claim_name(format!("{}::{}", short_name, item), &mangled, &import)?;
             }
-            Source::Glsl(_, _) => {
-                if let ProcessedShader::Glsl(import_source, _) = &imported_processed {
-                    final_string.push_str(import_source);
-                } else {
-                    return Err(ProcessShaderError::MismatchedImportFormat(import.clone()));
+            for item in &directive.items {
+                if !items.contains(item) {
+                    return Err(ProcessShaderError::UnknownImportItem {
+                        import: import.clone(),
+                        item: item.clone(),
+                    });
                 }
+                output.claim_name(item.clone(), &instance.mangle(item).unwrap(), &import)?;
             }
-            Source::SpirV(_) => {
-                return Err(ProcessShaderError::ShaderFormatDoesNotSupportImports);
+        }
+
+        Ok(())
+    }
+}
+
+/// The processed source of a single shader, before its names are rewritten.
+#[derive(Default)]
+struct ShaderOutput {
+    text: String,
+    /// Byte ranges of `text` that were expanded from imports, and were rewritten already.
+    imported: Vec<Range<usize>>,
+    /// Identifiers and qualified paths in the shader's own text, and what to replace them with.
+    names: HashMap<String, String>,
+}
+
+impl ShaderOutput {
+    fn push_import(&mut self, import_source: &str) {
+        let start = self.text.len();
+        self.text.push_str(import_source);
+        self.imported.push(start..self.text.len());
+    }
+
+    /// Makes `name` refer to the `mangled` item of `import`. Fails if `name` already refers to an
+    /// item of a different import instance.
+    fn claim_name(
+        &mut self,
+        name: String,
+        mangled: &str,
+        import: &ShaderImport,
+    ) -> Result<(), ProcessShaderError> {
+        match self.names.get(&name) {
+            Some(existing) if existing != mangled => Err(ProcessShaderError::AmbiguousImportName {
+                name,
+                import: import.clone(),
+            }),
+            Some(_) => Ok(()),
+            None => {
+                self.names.insert(name, mangled.to_string());
+                Ok(())
+            }
+        }
+    }
+
+    /// The shader's own text, without any imports.
+    fn own_text(&self) -> String {
+        let mut own_text = String::new();
+        let mut last_end = 0;
+        for range in &self.imported {
+            own_text.push_str(&self.text[last_end..range.start]);
+            last_end = range.end;
+        }
+        own_text.push_str(&self.text[last_end..]);
+        own_text
+    }
+
+    /// Applies `names` to the shader's own text.
+    fn finish(self) -> String {
+        if self.names.is_empty() {
+            return self.text;
+        }
+        let mut final_string = String::with_capacity(self.text.len());
+        let mut last_end = 0;
+        for range in &self.imported {
+            final_string.push_str(&namespace::rewrite_identifiers(
+                &self.text[last_end..range.start],
+                &self.names,
+            ));
+            final_string.push_str(&self.text[range.clone()]);
+            last_end = range.end;
+        }
+        final_string.push_str(&namespace::rewrite_identifiers(
+            &self.text[last_end..],
+            &self.names,
+        ));
+        final_string
+    }
+}
+
+/// The active shader defs while processing, keyed by name.
+type ShaderDefs = HashMap<String, ShaderDefVal>;
+
+fn collect_shader_defs(shader_defs: &[ShaderDefVal]) -> ShaderDefs {
+    // Later defs override earlier ones with the same name.
+    shader_defs
+        .iter()
+        .map(|def| (def.name().to_string(), def.clone()))
+        .collect()
+}
+
+/// The defs that processing with `shader_defs` actually sees, sorted by name, so that equivalent def
+/// lists compare equal.
+fn normalize_shader_defs(shader_defs: &[ShaderDefVal]) -> Vec<ShaderDefVal> {
+    let mut normalized = collect_shader_defs(shader_defs)
+        .into_values()
+        .collect::<Vec<_>>();
+    normalized.sort_by(|a, b| a.name().cmp(b.name()));
+    normalized
+}
+
+fn is_defined(name: &str, shader_defs: &ShaderDefs) -> bool {
+    shader_defs.get(name).is_some_and(|def| def.is_defined())
+}
+
+fn evaluate_if(expr: &expr::Expr, shader_defs: &ShaderDefs) -> bool {
+    expr.evaluate(&|name| {
+        shader_defs
+            .get(name)
+            .filter(|def| def.is_defined())
+            .map(|def| def.value_as_i64())
+    })
+}
+
+/// The state shared by a single [`ShaderProcessor::process`] call and all the imports it expands.
+struct ProcessContext<'a> {
+    provider: &'a dyn ShaderSourceProvider,
+    import_stack: ImportStack,
+    /// Import instances that were already expanded into the output.
+    emitted_imports: HashSet<ImportInstance>,
+    /// The items declared by each namespaced import instance that was expanded.
+    module_items: HashMap<ImportInstance, Vec<String>>,
+    /// How many imports were expanded so far.
+    import_count: usize,
+}
+
+/// Finds the shader that `importer` refers to with `import` in `provider`, returning the import it
+/// was resolved as. Asset paths are resolved relative to the importer's asset path first, then as
+/// given, and must be allowed by `policy`.
+fn resolve_import<'a>(
+    provider: &'a dyn ShaderSourceProvider,
+    import: &ShaderImport,
+    importer: &Shader,
+    policy: &ImportPolicy,
+) -> Result<Option<(ShaderImport, Cow<'a, Shader>)>, ProcessShaderError> {
+    let candidates = match import {
+        ShaderImport::AssetPath(path) => policy
+            .asset_path_candidates(importer.asset_path(), path)?
+            .into_iter()
+            .map(ShaderImport::AssetPath)
+            .collect(),
+        ShaderImport::Custom(_) => vec![import.clone()],
+    };
+    for candidate in candidates {
+        if let Some(shader) = provider.resolve(&candidate)? {
+            return Ok(Some((candidate, shader)));
+        }
+    }
+    Ok(None)
+}
+
+/// Called by [`visit_import_graph`] with each shader and the import it was resolved as, if any.
+type ImportGraphVisitor<'a> =
+    dyn FnMut(Option<&ShaderImport>, &Shader) -> Result<(), ProcessShaderError> + 'a;
+
+/// Calls `visit` with `shader` and then with every shader it imports, directly or through other
+/// imports, in all `#if*` branches. Each imported shader is visited once, along with the import it
+/// was resolved as. Imports that can't be resolved are skipped.
+fn visit_import_graph(
+    shader: &Shader,
+    provider: &dyn ShaderSourceProvider,
+    policy: &ImportPolicy,
+    visit: &mut ImportGraphVisitor,
+) -> Result<(), ProcessShaderError> {
+    fn visit_imports(
+        shader: &Shader,
+        provider: &dyn ShaderSourceProvider,
+        policy: &ImportPolicy,
+        visited: &mut HashSet<ShaderImport>,
+        visit: &mut ImportGraphVisitor,
+    ) -> Result<(), ProcessShaderError> {
+        let tree = shader.tree.as_ref().map_err(Clone::clone)?;
+        for directive in tree.imports() {
+            // Unresolvable imports are reported when processing reaches them.
+            if let Ok(Some((import, imported))) =
+                resolve_import(provider, &directive.import, shader, policy)
+            {
+                if visited.insert(import.clone()) {
+                    visit(Some(&import), &imported)?;
+                    visit_imports(&imported, provider, policy, visited, visit)?;
+                }
             }
         }
+        Ok(())
+    }
+
+    visit(None, shader)?;
+    visit_imports(shader, provider, policy, &mut HashSet::new(), visit)
+}
+
+/// The chain of imports currently being expanded, from the processed shader down to the innermost
+/// import. Used to detect import cycles and to limit the import depth.
+struct ImportStack {
+    root: Option<ShaderImport>,
+    imports: Vec<ShaderImport>,
+}
+
+impl ImportStack {
+    fn new(root: Option<ShaderImport>) -> Self {
+        Self {
+            root,
+            imports: Vec::new(),
+        }
+    }
 
+    fn push(&mut self, import: &ShaderImport, max_depth: usize) -> Result<(), ProcessShaderError> {
+        if self.root.as_ref() == Some(import) {
+            let mut cycle = self.chain();
+            cycle.push(import.clone());
+            return Err(ProcessShaderError::ImportCycle(cycle));
+        }
+        if let Some(position) = self.imports.iter().position(|active| active == import) {
+            let mut cycle = self.imports[position..].to_vec();
+            cycle.push(import.clone());
+            return Err(ProcessShaderError::ImportCycle(cycle));
+        }
+        if self.imports.len() >= max_depth {
+            let mut imports = self.chain();
+            imports.push(import.clone());
+            return Err(ProcessShaderError::ImportTooDeep { max_depth, imports });
+        }
+        self.imports.push(import.clone());
         Ok(())
     }
+
+    fn pop(&mut self) {
+        self.imports.pop();
+    }
+
+    fn chain(&self) -> Vec<ShaderImport> {
+        self.root.iter().chain(&self.imports).cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn process(
+        shader: &Shader,
+        shader_defs: &[ShaderDefVal],
+        imports: &[(&str, Shader)],
+    ) -> Result<String, ProcessShaderError> {
+        process_with(&ShaderProcessor::default(), shader, shader_defs, imports)
+    }
+
+    fn process_with(
+        processor: &ShaderProcessor,
+        shader: &Shader,
+        shader_defs: &[ShaderDefVal],
+        imports: &[(&str, Shader)],
+    ) -> Result<String, ProcessShaderError> {
+        let provider = imports
+            .iter()
+            .map(|(import, shader)| (ShaderImport::Custom(import.to_string()), shader.clone()))
+            .collect::<HashMap<_, _>>();
+        let processed = processor.process_with_provider(shader, shader_defs, &provider)?;
+        Ok(match processed {
+            ProcessedShader::Wgsl(source) | ProcessedShader::Glsl(source, _) => source.into(),
+            processed => panic!("unexpected processed shader {:?}", processed),
+        })
+    }
+
+    fn lines(source: &str) -> Vec<&str> {
+        source
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .collect()
+    }
+
+    #[test]
+    fn glsl_defines_are_left_to_naga() {
+        let source = [
+            "#version 450",
+            "#define PI 3.14159",
+            "#define SQ(x) ((x)*(x))",
+            "#define FOO",
+            "#ifdef FOO",
+            "foo",
+            "#endif",
+            "#undef FOO",
+            "#ifdef FOO",
+            "bar",
+            "#endif",
+            "#define N 4",
+            "#if N > 3 && defined(SQ) && defined(PI)",
+            "baz",
+            "#endif",
+            "void main() {}",
+        ];
+        let shader = Shader::from_glsl(source.join("\n"), naga::ShaderStage::Compute);
+        assert_eq!(
+            lines(&process(&shader, &[], &[]).unwrap()),
+            vec![
+                "#version 450",
+                "#define PI 3.14159",
+                "#define SQ(x) ((x)*(x))",
+                "#define FOO",
+                "foo",
+                "#undef FOO",
+                "#define N 4",
+                "baz",
+                "void main() {}",
+            ]
+        );
+
+        let wgsl = Shader::from_wgsl("#define FOO\n#ifdef FOO\nfoo\n#endif");
+        assert_eq!(lines(&process(&wgsl, &[], &[]).unwrap()), vec!["foo"]);
+    }
+
+    fn custom(paths: &[&str]) -> Vec<ShaderImport> {
+        paths
+            .iter()
+            .map(|path| ShaderImport::Custom(path.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn import_cycles_are_reported_with_their_path() {
+        // The processed shader itself is part of the cycle.
+        let a = Shader::from_wgsl("#define_import_path a\n#import b\nfn a() {}");
+        let b = Shader::from_wgsl("#import a\nfn b() {}");
+        let err = process(&a, &[], &[("a", a.clone()), ("b", b)]).unwrap_err();
+        assert_eq!(
+            err,
+            ProcessShaderError::ImportCycle(custom(&["a", "b", "a"]))
+        );
+        assert_eq!(err.to_string(), "Import cycle detected: a -> b -> a.");
+
+        // A cycle further down only lists the imports that are part of it.
+        let root = Shader::from_wgsl("#import x\nfn main() {}");
+        let x = Shader::from_wgsl("#import y\nfn x() {}");
+        let y = Shader::from_wgsl("#import z\nfn y() {}");
+        let z = Shader::from_wgsl("#import y\nfn z() {}");
+        assert_eq!(
+            process(&root, &[], &[("x", x), ("y", y), ("z", z)]),
+            Err(ProcessShaderError::ImportCycle(custom(&["y", "z", "y"])))
+        );
+    }
+
+    #[test]
+    fn import_depth_is_limited() {
+        let root = Shader::from_wgsl("#import m1");
+        let imports = [
+            ("m1", Shader::from_wgsl("#import m2")),
+            ("m2", Shader::from_wgsl("#import m3")),
+            ("m3", Shader::from_wgsl("fn m3() {}")),
+        ];
+        let processor = ShaderProcessor::default().with_max_import_depth(2);
+        assert_eq!(
+            process_with(&processor, &root, &[], &imports),
+            Err(ProcessShaderError::ImportTooDeep {
+                max_depth: 2,
+                imports: custom(&["m1", "m2", "m3"]),
+            })
+        );
+
+        let processor = ShaderProcessor::default().with_max_import_depth(3);
+        assert_eq!(
+            lines(&process_with(&processor, &root, &[], &imports).unwrap()),
+            vec!["fn m3() {}"]
+        );
+    }
+
+    #[test]
+    fn first_true_branch_wins() {
+        let shader = Shader::from_wgsl(
+            "#ifdef A\na\n#elif B > 1\nb\n#elifdef C\nc\n#elifndef D\nnot_d\n#else\nelse\n#endif",
+        );
+        let branch = |shader_defs: &[ShaderDefVal]| process(&shader, shader_defs, &[]).unwrap();
+        let c = ShaderDefVal::from("C");
+        let d = ShaderDefVal::from("D");
+        assert_eq!(lines(&branch(&["A".into(), c.clone()])), vec!["a"]);
+        assert_eq!(
+            lines(&branch(&[ShaderDefVal::Int("B".into(), 2), c.clone()])),
+            vec!["b"]
+        );
+        assert_eq!(
+            lines(&branch(&[ShaderDefVal::Int("B".into(), 1), c.clone()])),
+            vec!["c"]
+        );
+        assert_eq!(lines(&branch(&[])), vec!["not_d"]);
+        assert_eq!(lines(&branch(&[d])), vec!["else"]);
+    }
+
+    #[test]
+    fn misplaced_else_and_elif() {
+        let err = |source: &'static str| process(&Shader::from_wgsl(source), &[], &[]).unwrap_err();
+        assert_eq!(err("#else\n#endif"), ProcessShaderError::ElseWithoutIf);
+        assert_eq!(
+            err("#elifdef A\n#endif"),
+            ProcessShaderError::ElifWithoutIf("elifdef".into())
+        );
+        assert_eq!(
+            err("#ifdef A\n#else\n#elif B\n#endif"),
+            ProcessShaderError::ElifAfterElse("elif".into())
+        );
+        assert_eq!(
+            err("#ifdef A\n#else\n#elifndef B\n#endif"),
+            ProcessShaderError::ElifAfterElse("elifndef".into())
+        );
+        assert_eq!(
+            err("#ifdef A\n#else\n#else\n#endif"),
+            ProcessShaderError::ElseAfterElse
+        );
+        assert_eq!(
+            err("#ifdef A\n#elif B\n"),
+            ProcessShaderError::NotEnoughEndIfs
+        );
+    }
+
+    #[test]
+    fn conditional_nesting_is_limited() {
+        let nested = |depth: usize| {
+            format!(
+                "{}fn f() {{}}\n{}",
+                "#ifdef A\n".repeat(depth),
+                "#endif\n".repeat(depth)
+            )
+        };
+        let defs = [ShaderDefVal::from("A")];
+        let shader = Shader::from_wgsl(nested(tree::MAX_NESTING_DEPTH));
+        assert_eq!(
+            lines(&process(&shader, &defs, &[]).unwrap()),
+            vec!["fn f() {}"]
+        );
+        let shader = Shader::from_wgsl(nested(50_000));
+        assert_eq!(
+            process(&shader, &defs, &[]).unwrap_err(),
+            ProcessShaderError::ConditionalsTooDeep(tree::MAX_NESTING_DEPTH)
+        );
+    }
+
+    #[test]
+    fn diamond_imports_are_expanded_once() {
+        let root = Shader::from_wgsl("#import left\n#import right\n#import common\nfn main() {}");
+        let imports = |common: Shader| {
+            [
+                ("left", Shader::from_wgsl("#import common\nfn left() {}")),
+                ("right", Shader::from_wgsl("#import common\nfn right() {}")),
+                ("common", common),
+            ]
+        };
+
+        let common = Shader::from_wgsl("fn common() {}");
+        assert!(!common.allows_multiple_imports());
+        assert_eq!(
+            lines(&process(&root, &[], &imports(common)).unwrap()),
+            vec![
+                "fn common() {}",
+                "fn left() {}",
+                "fn right() {}",
+                "fn main() {}"
+            ]
+        );
+
+        let common = Shader::from_wgsl("#allow_multiple_imports\nconst COMMON: u32 = 1u;");
+        assert!(common.allows_multiple_imports());
+        assert_eq!(
+            lines(&process(&root, &[], &imports(common)).unwrap()),
+            vec![
+                "const COMMON: u32 = 1u;",
+                "fn left() {}",
+                "const COMMON: u32 = 1u;",
+                "fn right() {}",
+                "const COMMON: u32 = 1u;",
+                "fn main() {}",
+            ]
+        );
+    }
+
+    #[test]
+    fn aliased_custom_imports_are_namespaced() {
+        let root = Shader::from_wgsl(
+            "#import noise_v1 as n1\n#import noise_v2 as n2\nfn main() { n1::noise(); n2::noise(); }",
+        );
+        let imports = [
+            ("noise_v1", Shader::from_wgsl("fn noise() {}")),
+            ("noise_v2", Shader::from_wgsl("fn noise() { let v = 2; }")),
+        ];
+        assert_eq!(
+            lines(&process(&root, &[], &imports).unwrap()),
+            vec![
+                "fn noise_uv1__noise() {}",
+                "fn noise_uv2__noise() { let v = 2; }",
+                "fn main() { noise_uv1__noise(); noise_uv2__noise(); }",
+            ]
+        );
+    }
+
+    #[test]
+    fn defs_are_substituted() {
+        let shader = Shader::from_wgsl(
+            "let N: i32 = #{N};\nlet S: u32 = #{S}; // #{S}\nlet B = #{B};\n#define L 3\nlet L = #{L};",
+        );
+        let defs = [
+            ShaderDefVal::Int("N".into(), -2),
+            ShaderDefVal::UInt("S".into(), 7),
+            ShaderDefVal::Bool("B".into(), false),
+        ];
+        assert_eq!(
+            lines(&process(&shader, &defs, &[]).unwrap()),
+            vec![
+                "let N: i32 = -2;",
+                "let S: u32 = 7u; // 7u",
+                "let B = false;",
+                "let L = 3;"
+            ]
+        );
+        assert_eq!(
+            process(&shader, &defs[..2], &[]).unwrap_err(),
+            ProcessShaderError::UndefinedSubstitution("B".into())
+        );
+    }
+
+    #[test]
+    fn defs_are_substituted_in_imports() {
+        let root = Shader::from_wgsl("#define SCALE 4\n#import consts\nfn main() {}");
+        let consts = Shader::from_wgsl("let SIZE: u32 = #{SIZE};\nlet SCALE: i32 = #{SCALE};");
+        assert_eq!(
+            lines(
+                &process(
+                    &root,
+                    &[ShaderDefVal::UInt("SIZE".into(), 8)],
+                    &[("consts", consts.clone())]
+                )
+                .unwrap()
+            ),
+            vec!["let SIZE: u32 = 8u;", "let SCALE: i32 = 4;", "fn main() {}"]
+        );
+        assert_eq!(
+            process(&root, &[], &[("consts", consts)]).unwrap_err(),
+            ProcessShaderError::UndefinedSubstitution("SIZE".into())
+        );
+    }
+
+    fn shadows() -> (&'static str, Shader) {
+        (
+            "lib::shadows",
+            Shader::from_wgsl(
+                "fn taps() -> i32 { return #{TAPS}; }\n#ifdef PCF\nfn pcf() {}\n#endif",
+            ),
+        )
+    }
+
+    #[test]
+    fn with_clauses_override_and_remove_defs() {
+        let defs = [
+            ShaderDefVal::Int("TAPS".into(), 4),
+            ShaderDefVal::from("PCF"),
+        ];
+        let root = Shader::from_wgsl(
+            "#import lib::shadows with TAPS=8, !PCF\nfn main() { shadows::taps(); }",
+        );
+        assert_eq!(
+            lines(&process(&root, &defs, &[shadows()]).unwrap()),
+            vec![
+                "fn lib_sshadows_w_dPCF_r_dTAPS_i8__taps() -> i32 { return 8; }",
+                "fn main() { lib_sshadows_w_dPCF_r_dTAPS_i8__taps(); }",
+            ]
+        );
+
+        // The importing shader's defs are unchanged.
+        let root = Shader::from_wgsl(
+            "#import lib::shadows with !PCF\n#ifdef PCF\nfn main() { shadows::taps(); }\n#endif",
+        );
+        assert_eq!(
+            lines(&process(&root, &defs, &[shadows()]).unwrap()),
+            vec![
+                "fn lib_sshadows_w_dPCF_r__taps() -> i32 { return 4; }",
+                "fn main() { lib_sshadows_w_dPCF_r__taps(); }",
+            ]
+        );
+    }
+
+    #[test]
+    fn differently_configured_imports_get_their_own_names() {
+        let root = Shader::from_wgsl(
+            "#import lib::shadows with TAPS=4 as low\n#import lib::shadows with TAPS=8 as high\n\
+             fn main() { low::taps(); high::taps(); }",
+        );
+        assert_eq!(
+            lines(&process(&root, &[], &[shadows()]).unwrap()),
+            vec![
+                "fn lib_sshadows_w_dTAPS_i4__taps() -> i32 { return 4; }",
+                "fn lib_sshadows_w_dTAPS_i8__taps() -> i32 { return 8; }",
+                "fn main() { lib_sshadows_w_dTAPS_i4__taps(); lib_sshadows_w_dTAPS_i8__taps(); }",
+            ]
+        );
+
+        let unaliased = Shader::from_wgsl(
+            "#import lib::shadows with TAPS=4\n#import lib::shadows with TAPS=8\nfn main() {}",
+        );
+        assert_eq!(
+            process(&unaliased, &[], &[shadows()]).unwrap_err(),
+            ProcessShaderError::AmbiguousImportName {
+                name: "lib::shadows::taps".into(),
+                import: ShaderImport::Custom("lib::shadows".into()),
+            }
+        );
+    }
+
+    #[test]
+    fn with_clauses_need_a_namespace() {
+        let root = Shader::from_wgsl("#import common with TAPS=8\nfn main() {}");
+        let common = Shader::from_wgsl("fn common() {}");
+        assert_eq!(
+            process(&root, &[], &[("common", common.clone())]).unwrap_err(),
+            ProcessShaderError::ImportDefsNotNamespaced(ShaderImport::Custom("common".into()))
+        );
+        let aliased =
+            Shader::from_wgsl("#import common with TAPS=8 as c\nfn main() { c::common(); }");
+        assert_eq!(
+            lines(&process(&aliased, &[], &[("common", common)]).unwrap()),
+            vec![
+                "fn common_w_dTAPS_i8__common() {}",
+                "fn main() { common_w_dTAPS_i8__common(); }",
+            ]
+        );
+    }
+
+    #[test]
+    fn imports_with_defs_skip_inactive_blocks() {
+        let shader = Shader::from_wgsl(
+            "#import common\n#ifdef SHADOWS\n#import lib::shadows\n#else\n#import lib::flat\n#endif\n\
+             #if TAPS > 4\n#import lib::pcf\n#endif",
+        );
+        assert_eq!(
+            shader.imports().cloned().collect::<Vec<_>>(),
+            custom(&["common", "lib::shadows", "lib::flat", "lib::pcf"])
+        );
+        assert_eq!(
+            shader.imports_with_defs(&[]).unwrap(),
+            custom(&["common", "lib::flat"])
+        );
+        assert_eq!(
+            shader
+                .imports_with_defs(&["SHADOWS".into(), ShaderDefVal::Int("TAPS".into(), 8)])
+                .unwrap(),
+            custom(&["common", "lib::shadows", "lib::pcf"])
+        );
+    }
+
+    #[test]
+    fn converted_modules_fall_back_to_wgsl() {
+        let naga_module = |source: &str| {
+            ProcessedShader::NagaModule(NagaModule::new(
+                naga::front::wgsl::parse_str(source).unwrap(),
+            ))
+        };
+        fn descriptor_source(
+            shader: &ProcessedShader,
+            format: ConvertedShaderFormat,
+        ) -> wgpu::ShaderSource<'_> {
+            let options = ModuleDescriptorOptions {
+                format,
+                ..ModuleDescriptorOptions::default()
+            };
+            shader
+                .get_module_descriptor_with_options(&options)
+                .unwrap()
+                .source
+        }
+
+        let glsl = ProcessedShader::Glsl(
+            "#version 450\nlayout(location = 0) out vec4 color;\nvoid main() { color = vec4(1.0); }"
+                .into(),
+            naga::ShaderStage::Fragment,
+        );
+        assert!(matches!(
+            glsl.get_module_descriptor().unwrap().source,
+            wgpu::ShaderSource::SpirV(_)
+        ));
+        assert!(matches!(
+            descriptor_source(&glsl, ConvertedShaderFormat::Wgsl),
+            wgpu::ShaderSource::Wgsl(_)
+        ));
+
+        // Naga can't write `countOneBits` as SPIR-V.
+        let count_bits = naga_module(
+            "var<private> bits: u32;\n[[stage(compute), workgroup_size(1)]]\n\
+             fn main() { bits = countOneBits(bits); }",
+        );
+        match descriptor_source(&count_bits, ConvertedShaderFormat::SpirV) {
+            wgpu::ShaderSource::Wgsl(source) => assert!(source.contains("countOneBits(")),
+            _ => panic!("expected WGSL"),
+        }
+    }
 }
@@ -0,0 +1,23 @@
+/// Lexically normalizes a `/`-separated asset path: empty and `.` components are dropped and `..`
+/// removes the preceding component. A `..` with nothing left to remove is kept.
+pub(super) fn normalize(path: &str) -> String {
+    let mut components = Vec::<&str>::new();
+    for component in path.split(['/', '\\']) {
+        match component {
+            "" | "." => {}
+            ".." if components.last().is_some_and(|last| *last != "..") => {
+                components.pop();
+            }
+            component => components.push(component),
+        }
+    }
+    components.join("/")
+}
+
+/// Resolves `import` relative to the directory of the asset at `importer`.
+pub(super) fn join_relative(importer: &str, import: &str) -> String {
+    let directory = importer
+        .rsplit_once('/')
+        .map_or("", |(directory, _)| directory);
+    normalize(&format!("{}/{}", directory, import))
+}
@@ -0,0 +1,417 @@
+use std::sync::Arc;
+
+use naga::{
+    back::{glsl, hlsl, msl},
+    Arena, Constant, EntryPoint, Function, Module, Type, TypeInner, UniqueArena,
+};
+
+use super::{AsModuleDescriptorError, ShaderReflectError, ShaderReflection};
+
+/// Options for [`ShaderReflection::get_hlsl`].
+#[derive(Debug, Clone, Default)]
+pub struct HlslOptions {
+    /// The shader model, bindings and other settings passed to naga's HLSL backend.
+    pub backend: hlsl::Options,
+    /// The only entry point to translate. By default all of them are.
+    pub entry_point: Option<String>,
+}
+
+impl HlslOptions {
+    pub fn with_shader_model(mut self, shader_model: hlsl::ShaderModel) -> Self {
+        self.backend.shader_model = shader_model;
+        self
+    }
+
+    pub fn with_entry_point(mut self, entry_point: impl Into<String>) -> Self {
+        self.entry_point = Some(entry_point.into());
+        self
+    }
+}
+
+/// Options for [`ShaderReflection::get_msl`].
+#[derive(Debug, Clone, Default)]
+pub struct MslOptions {
+    /// The language version, resource slots and other settings passed to naga's MSL backend.
+    pub backend: msl::Options,
+    pub pipeline: msl::PipelineOptions,
+    /// The only entry point to translate. By default all of them are.
+    pub entry_point: Option<String>,
+}
+
+impl MslOptions {
+    /// Sets the (major, minor) Metal Shading Language version.
+    pub fn with_lang_version(mut self, lang_version: (u8, u8)) -> Self {
+        self.backend.lang_version = lang_version;
+        self
+    }
+
+    pub fn with_entry_point(mut self, entry_point: impl Into<String>) -> Self {
+        self.entry_point = Some(entry_point.into());
+        self
+    }
+}
+
+/// Options for [`ShaderReflection::get_glsl`].
+#[derive(Debug, Clone, Default)]
+pub struct GlslOptions {
+    /// The GLSL version, bindings and writer flags passed to naga's GLSL backend.
+    pub backend: glsl::Options,
+    /// The entry point to translate. GLSL output contains a single entry point, so this is
+    /// required if the module has more than one.
+    pub entry_point: Option<String>,
+}
+
+impl GlslOptions {
+    pub fn with_version(mut self, version: glsl::Version) -> Self {
+        self.backend.version = version;
+        self
+    }
+
+    pub fn with_entry_point(mut self, entry_point: impl Into<String>) -> Self {
+        self.entry_point = Some(entry_point.into());
+        self
+    }
+}
+
+impl ShaderReflection {
+    pub fn get_hlsl(&self, options: &HlslOptions) -> Result<String, AsModuleDescriptorError> {
+        let selected = self.select_entry_point(options.entry_point.as_deref())?;
+        let reflection = selected.as_ref().unwrap_or(self);
+        let mut hlsl = String::new();
+        let info = hlsl::Writer::new(&mut hlsl, &options.backend)
+            .write(&reflection.module, &reflection.module_info)?;
+        for (index, result) in info.entry_point_names.into_iter().enumerate() {
+            if let Err(source) = result {
+                return Err(AsModuleDescriptorError::HlslEntryPoint {
+                    entry_point: reflection.module.entry_points[index].name.clone(),
+                    source,
+                });
+            }
+        }
+        Ok(hlsl)
+    }
+
+    pub fn get_msl(&self, options: &MslOptions) -> Result<String, AsModuleDescriptorError> {
+        let selected = self.select_entry_point(options.entry_point.as_deref())?;
+        let reflection = selected.as_ref().unwrap_or(self);
+        let (msl, info) = msl::write_string(
+            &reflection.module,
+            &reflection.module_info,
+            &options.backend,
+            &options.pipeline,
+        )?;
+        for (index, result) in info.entry_point_names.into_iter().enumerate() {
+            if let Err(source) = result {
+                return Err(AsModuleDescriptorError::MslEntryPoint {
+                    entry_point: reflection.module.entry_points[index].name.clone(),
+                    source,
+                });
+            }
+        }
+        Ok(msl)
+    }
+
+    pub fn get_glsl(&self, options: &GlslOptions) -> Result<String, AsModuleDescriptorError> {
+        let entry_point = match (&options.entry_point, self.module.entry_points.as_slice()) {
+            (Some(name), entry_points) => entry_points
+                .iter()
+                .find(|entry_point| entry_point.name == *name)
+                .ok_or_else(|| AsModuleDescriptorError::UnknownEntryPoint(name.clone()))?,
+            (None, [entry_point]) => entry_point,
+            (None, _) => return Err(AsModuleDescriptorError::EntryPointRequired),
+        };
+        let pipeline_options = glsl::PipelineOptions {
+            shader_stage: entry_point.stage,
+            entry_point: entry_point.name.clone(),
+        };
+        let mut glsl = String::new();
+        glsl::Writer::new(
+            &mut glsl,
+            &self.module,
+            &self.module_info,
+            &options.backend,
+            &pipeline_options,
+        )?
+        .write()?;
+        Ok(glsl)
+    }
+
+    /// A copy of this reflection with only the entry point named `entry_point`, validated again
+    /// with the same config. `None` if no entry point is selected.
+    fn select_entry_point(
+        &self,
+        entry_point: Option<&str>,
+    ) -> Result<Option<ShaderReflection>, AsModuleDescriptorError> {
+        let name = match entry_point {
+            Some(name) => name,
+            None => return Ok(None),
+        };
+        if !self
+            .module
+            .entry_points
+            .iter()
+            .any(|entry_point| entry_point.name == name)
+        {
+            return Err(AsModuleDescriptorError::UnknownEntryPoint(name.to_string()));
+        }
+        let mut module = clone_module(&self.module);
+        module
+            .entry_points
+            .retain(|entry_point| entry_point.name == name);
+        let module_info = self
+            .validation
+            .validator()
+            .validate(&module)
+            .map_err(ShaderReflectError::from)?;
+        Ok(Some(ShaderReflection {
+            module: Arc::new(module),
+            module_info: Arc::new(module_info),
+            validation: self.validation,
+        }))
+    }
+}
+
+/// naga's IR isn't `Clone`, so this copies it item by item. Every arena is filled in the original
+/// order, so the handles in the copy point at the same items.
+fn clone_module(module: &Module) -> Module {
+    let mut types = UniqueArena::new();
+    for (handle, ty) in module.types.iter() {
+        let inner = match &ty.inner {
+            TypeInner::Scalar { kind, width } => TypeInner::Scalar {
+                kind: *kind,
+                width: *width,
+            },
+            TypeInner::Vector { size, kind, width } => TypeInner::Vector {
+                size: *size,
+                kind: *kind,
+                width: *width,
+            },
+            TypeInner::Matrix {
+                columns,
+                rows,
+                width,
+            } => TypeInner::Matrix {
+                columns: *columns,
+                rows: *rows,
+                width: *width,
+            },
+            TypeInner::Atomic { kind, width } => TypeInner::Atomic {
+                kind: *kind,
+                width: *width,
+            },
+            TypeInner::Pointer { base, class } => TypeInner::Pointer {
+                base: *base,
+                class: *class,
+            },
+            TypeInner::ValuePointer {
+                size,
+                kind,
+                width,
+                class,
+            } => TypeInner::ValuePointer {
+                size: *size,
+                kind: *kind,
+                width: *width,
+                class: *class,
+            },
+            TypeInner::Array { base, size, stride } => TypeInner::Array {
+                base: *base,
+                size: *size,
+                stride: *stride,
+            },
+            TypeInner::Struct { members, span } => TypeInner::Struct {
+                members: members.clone(),
+                span: *span,
+            },
+            TypeInner::Image {
+                dim,
+                arrayed,
+                class,
+            } => TypeInner::Image {
+                dim: *dim,
+                arrayed: *arrayed,
+                class: *class,
+            },
+            TypeInner::Sampler { comparison } => TypeInner::Sampler {
+                comparison: *comparison,
+            },
+        };
+        let ty = Type {
+            name: ty.name.clone(),
+            inner,
+        };
+        types.insert(ty, module.types.get_span(handle));
+    }
+    let mut constants = Arena::new();
+    for (handle, constant) in module.constants.iter() {
+        let constant = Constant {
+            name: constant.name.clone(),
+            specialization: constant.specialization,
+            inner: constant.inner.clone(),
+        };
+        constants.append(constant, module.constants.get_span(handle));
+    }
+    let mut functions = Arena::new();
+    for (handle, function) in module.functions.iter() {
+        functions.append(clone_function(function), module.functions.get_span(handle));
+    }
+    Module {
+        types,
+        constants,
+        global_variables: clone_arena(&module.global_variables),
+        functions,
+        entry_points: module
+            .entry_points
+            .iter()
+            .map(|entry_point| EntryPoint {
+                name: entry_point.name.clone(),
+                stage: entry_point.stage,
+                early_depth_test: entry_point.early_depth_test,
+                workgroup_size: entry_point.workgroup_size,
+                function: clone_function(&entry_point.function),
+            })
+            .collect(),
+    }
+}
+
+fn clone_function(function: &Function) -> Function {
+    Function {
+        name: function.name.clone(),
+        arguments: function.arguments.clone(),
+        result: function.result.clone(),
+        local_variables: clone_arena(&function.local_variables),
+        expressions: clone_arena(&function.expressions),
+        named_expressions: function.named_expressions.clone(),
+        body: function.body.clone(),
+    }
+}
+
+fn clone_arena<T: Clone>(arena: &Arena<T>) -> Arena<T> {
+    let mut clone = Arena::new();
+    for (handle, value) in arena.iter() {
+        clone.append(value.clone(), arena.get_span(handle));
+    }
+    clone
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::super::{Shader, ShaderImport, ShaderProcessor};
+    use super::*;
+
+    const SOURCE: &str = "
+        struct Camera { view_proj: mat4x4<f32>; };
+        struct Lights { colors: array<vec4<f32>, 4>; };
+        [[group(0), binding(0)]] var<uniform> camera: Camera;
+        [[group(0), binding(1)]] var<uniform> lights: Lights;
+        let AMBIENT: f32 = 0.1;
+
+        fn light(index: i32) -> vec4<f32> {
+            var color = lights.colors[index];
+            return color * AMBIENT;
+        }
+
+        [[stage(vertex)]]
+        fn vs_main([[location(0)]] position: vec3<f32>) -> [[builtin(position)]] vec4<f32> {
+            return camera.view_proj * vec4<f32>(position, 1.0);
+        }
+
+        [[stage(fragment)]]
+        fn fs_main() -> [[location(0)]] vec4<f32> {
+            let color = light(0) + light(1);
+            return color;
+        }
+    ";
+
+    fn reflect() -> ShaderReflection {
+        ShaderProcessor::default()
+            .process_with_provider(
+                &Shader::from_wgsl(SOURCE),
+                &[],
+                &HashMap::<ShaderImport, Shader>::new(),
+            )
+            .unwrap()
+            .reflect()
+            .unwrap()
+    }
+
+    #[test]
+    fn cloned_modules_are_identical() {
+        let reflection = reflect();
+        let clone = clone_module(&reflection.module);
+        let clone_info = reflection.validation.validator().validate(&clone).unwrap();
+        let wgsl = |module: &Module, info| {
+            naga::back::wgsl::write_string(module, info, naga::back::wgsl::WriterFlags::empty())
+                .unwrap()
+        };
+        assert_eq!(
+            wgsl(&clone, &clone_info),
+            wgsl(&reflection.module, &reflection.module_info)
+        );
+    }
+
+    #[test]
+    fn entry_points_are_selected_by_name() {
+        let reflection = reflect();
+        assert!(reflection.select_entry_point(None).unwrap().is_none());
+        let selected = reflection
+            .select_entry_point(Some("fs_main"))
+            .unwrap()
+            .unwrap();
+        let names = selected
+            .module
+            .entry_points
+            .iter()
+            .map(|entry_point| entry_point.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["fs_main"]);
+        assert!(matches!(
+            reflection.select_entry_point(Some("cs_main")),
+            Err(AsModuleDescriptorError::UnknownEntryPoint(name)) if name == "cs_main"
+        ));
+    }
+
+    #[test]
+    fn hlsl_output() {
+        let reflection = reflect();
+        let all = reflection.get_hlsl(&HlslOptions::default()).unwrap();
+        assert!(all.contains("vs_main") && all.contains("fs_main"));
+        let vertex = reflection
+            .get_hlsl(&HlslOptions::default().with_entry_point("vs_main"))
+            .unwrap();
+        assert!(vertex.contains("vs_main") && !vertex.contains("fs_main"));
+    }
+
+    #[test]
+    fn msl_output() {
+        let reflection = reflect();
+        let options = MslOptions::default().with_lang_version((2, 0));
+        let all = reflection.get_msl(&options).unwrap();
+        assert!(all.contains("vs_main") && all.contains("fs_main"));
+        let fragment = reflection
+            .get_msl(&options.with_entry_point("fs_main"))
+            .unwrap();
+        assert!(fragment.contains("fs_main") && !fragment.contains("vs_main"));
+    }
+
+    #[test]
+    fn glsl_output_needs_a_single_entry_point() {
+        let reflection = reflect();
+        assert!(matches!(
+            reflection.get_glsl(&GlslOptions::default()),
+            Err(AsModuleDescriptorError::EntryPointRequired)
+        ));
+        assert!(matches!(
+            reflection.get_glsl(&GlslOptions::default().with_entry_point("cs_main")),
+            Err(AsModuleDescriptorError::UnknownEntryPoint(name)) if name == "cs_main"
+        ));
+        let options = GlslOptions::default()
+            .with_version(glsl::Version::Desktop(450))
+            .with_entry_point("vs_main");
+        let vertex = reflection.get_glsl(&options).unwrap();
+        assert!(vertex.starts_with("#version 450"));
+        assert!(vertex.contains("gl_Position"));
+    }
+}
@@ -0,0 +1,552 @@
+use super::ProcessShaderError;
+
+/// How deeply parentheses and unary operators can nest in an `#if` expression. Each level is a
+/// recursive call of the parser and a level of [`Expr`], so this bounds the stack usage of
+/// parsing, evaluating and dropping expressions from untrusted shaders.
+const MAX_DEPTH: usize = 256;
+
+/// A single lexical token of an `#if` expression.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Int(i64),
+    Ident(String),
+    Not,
+    Minus,
+    And,
+    Or,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    LParen,
+    RParen,
+}
+
+/// A parsed `#if` expression.
+///
+/// Supports `defined(NAME)` (or `defined NAME`), `!`, unary `-`, `&&`, `||`, parentheses, integer
+/// literals, `true`/`false` and the comparison operators `==`, `!=`, `<`, `<=`, `>` and `>=`. A bare
+/// identifier evaluates to the value of the def, or `0` when it is not defined.
+///
+/// Chains of binary operators are stored flat, so only parentheses and unary operators make the
+/// tree deeper.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) enum Expr {
+    Int(i64),
+    /// A bare def name.
+    Def(String),
+    Defined(String),
+    Not(Box<Expr>),
+    Neg(Box<Expr>),
+    /// The operands of `&&`.
+    And(Vec<Expr>),
+    /// The operands of `||`.
+    Or(Vec<Expr>),
+    /// A left-associative chain of comparisons: `A < B == 1` compares `A < B` with `1`.
+    Compare(Box<Expr>, Vec<(Comparison, Expr)>),
+}
+
+impl Expr {
+    /// Parses the expression of an `#if` directive.
+    pub(super) fn parse(expression: &str) -> Result<Self, ProcessShaderError> {
+        let tokens = tokenize(expression)?;
+        let mut parser = Parser {
+            expression,
+            tokens: &tokens,
+            position: 0,
+            depth: 0,
+        };
+        let expr = parser.parse_or()?;
+        if let Some(token) = parser.peek() {
+            return Err(parser.unexpected(token));
+        }
+        Ok(expr)
+    }
+
+    /// Whether the guarded block is active, given the values of the defined defs.
+    pub(super) fn evaluate(&self, lookup: &dyn Fn(&str) -> Option<i64>) -> bool {
+        self.value(lookup) != 0
+    }
+
+    fn value(&self, lookup: &dyn Fn(&str) -> Option<i64>) -> i64 {
+        match self {
+            Expr::Int(value) => *value,
+            Expr::Def(name) => lookup(name).unwrap_or(0),
+            Expr::Defined(name) => lookup(name).is_some() as i64,
+            Expr::Not(expr) => (expr.value(lookup) == 0) as i64,
+            Expr::Neg(expr) => expr.value(lookup).wrapping_neg(),
+            Expr::And(exprs) => exprs.iter().all(|expr| expr.value(lookup) != 0) as i64,
+            Expr::Or(exprs) => exprs.iter().any(|expr| expr.value(lookup) != 0) as i64,
+            Expr::Compare(first, rest) => rest
+                .iter()
+                .fold(first.value(lookup), |value, (comparison, rhs)| {
+                    comparison.apply(value, rhs.value(lookup)) as i64
+                }),
+        }
+    }
+
+    /// The integer literal this is, possibly negated, as in `3` or `-1`.
+    fn literal(&self) -> Option<i64> {
+        match self {
+            Expr::Int(value) => Some(*value),
+            Expr::Neg(expr) => match **expr {
+                Expr::Int(value) => Some(value.wrapping_neg()),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// The defs named in the expression, in order, each with the integer literal it's directly
+    /// compared against, as in `NAME == 3`, `3 < NAME` or `NAME > -1`.
+    pub(super) fn references(&self) -> Vec<(String, Option<(Comparison, i64)>)> {
+        let mut references = Vec::new();
+        self.collect_references(None, &mut references);
+        references
+    }
+
+    /// Adds the defs named in the expression to `references`. `compared` is set if the expression
+    /// is a def compared against a literal.
+    fn collect_references(
+        &self,
+        compared: Option<(Comparison, i64)>,
+        references: &mut Vec<(String, Option<(Comparison, i64)>)>,
+    ) {
+        match self {
+            Expr::Int(_) => {}
+            Expr::Def(name) => references.push((name.clone(), compared)),
+            Expr::Defined(name) => references.push((name.clone(), None)),
+            Expr::Not(expr) | Expr::Neg(expr) => expr.collect_references(None, references),
+            Expr::And(exprs) | Expr::Or(exprs) => {
+                for expr in exprs {
+                    expr.collect_references(None, references);
+                }
+            }
+            Expr::Compare(first, rest) => {
+                // Only the first comparison of a chain has a def or literal on its left; later
+                // ones compare the result of the previous one.
+                let (comparison, second) = &rest[0];
+                first.collect_references(
+                    second.literal().map(|literal| (*comparison, literal)),
+                    references,
+                );
+                second.collect_references(
+                    first.literal().map(|literal| (comparison.flip(), literal)),
+                    references,
+                );
+                for (_, expr) in &rest[1..] {
+                    expr.collect_references(None, references);
+                }
+            }
+        }
+    }
+}
+
+/// How a def is compared against an integer literal in an `#if` expression, as if the def was on
+/// the left: `3 < NAME` is recorded as `Gt`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub(super) enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Comparison {
+    fn apply(self, lhs: i64, rhs: i64) -> bool {
+        match self {
+            Comparison::Eq => lhs == rhs,
+            Comparison::Ne => lhs != rhs,
+            Comparison::Lt => lhs < rhs,
+            Comparison::Le => lhs <= rhs,
+            Comparison::Gt => lhs > rhs,
+            Comparison::Ge => lhs >= rhs,
+        }
+    }
+
+    fn from_token(token: &Token) -> Option<Self> {
+        Some(match token {
+            Token::Eq => Comparison::Eq,
+            Token::Ne => Comparison::Ne,
+            Token::Lt => Comparison::Lt,
+            Token::Le => Comparison::Le,
+            Token::Gt => Comparison::Gt,
+            Token::Ge => Comparison::Ge,
+            _ => return None,
+        })
+    }
+
+    /// The comparison with its operands swapped.
+    fn flip(self) -> Self {
+        match self {
+            Comparison::Lt => Comparison::Gt,
+            Comparison::Le => Comparison::Ge,
+            Comparison::Gt => Comparison::Lt,
+            Comparison::Ge => Comparison::Le,
+            comparison => comparison,
+        }
+    }
+
+    /// Values of the def on both sides of the comparison with `literal`: the literal itself, and
+    /// for an ordering the closest value on the other side of the boundary.
+    pub(super) fn boundary_values(self, literal: i64) -> impl Iterator<Item = i64> {
+        let across = match self {
+            Comparison::Eq | Comparison::Ne => None,
+            Comparison::Gt | Comparison::Le => literal.checked_add(1),
+            Comparison::Lt | Comparison::Ge => literal.checked_sub(1),
+        };
+        Some(literal).into_iter().chain(across)
+    }
+}
+
+fn tokenize(expression: &str) -> Result<Vec<Token>, ProcessShaderError> {
+    let mut tokens = Vec::new();
+    let mut chars = expression.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            '/' if expression[start..].starts_with("//") => break,
+            '(' => Token::LParen,
+            ')' => Token::RParen,
+            '!' if next_is(&mut chars, '=') => Token::Ne,
+            '!' => Token::Not,
+            '-' => Token::Minus,
+            '=' if next_is(&mut chars, '=') => Token::Eq,
+            '<' if next_is(&mut chars, '=') => Token::Le,
+            '<' => Token::Lt,
+            '>' if next_is(&mut chars, '=') => Token::Ge,
+            '>' => Token::Gt,
+            '&' if next_is(&mut chars, '&') => Token::And,
+            '|' if next_is(&mut chars, '|') => Token::Or,
+            c if c.is_ascii_digit() => {
+                let mut end = start + c.len_utf8();
+                while let Some(&(i, c)) = chars.peek() {
+                    if !c.is_ascii_alphanumeric() {
+                        break;
+                    }
+                    end = i + c.len_utf8();
+                    chars.next();
+                }
+                let literal = &expression[start..end];
+                let value = literal.trim_end_matches(['u', 'i']).parse().map_err(|_| {
+                    ProcessShaderError::InvalidIfLiteral {
+                        expression: expression.to_string(),
+                        literal: literal.to_string(),
+                    }
+                })?;
+                Token::Int(value)
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let mut end = start + c.len_utf8();
+                while let Some(&(i, c)) = chars.peek() {
+                    if !(c.is_alphanumeric() || c == '_') {
+                        break;
+                    }
+                    end = i + c.len_utf8();
+                    chars.next();
+                }
+                Token::Ident(expression[start..end].to_string())
+            }
+            _ => {
+                return Err(ProcessShaderError::UnexpectedIfToken {
+                    expression: expression.to_string(),
+                    token: c.to_string(),
+                })
+            }
+        };
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+fn next_is(chars: &mut std::iter::Peekable<std::str::CharIndices>, expected: char) -> bool {
+    if matches!(chars.peek(), Some(&(_, c)) if c == expected) {
+        chars.next();
+        true
+    } else {
+        false
+    }
+}
+
+struct Parser<'a> {
+    expression: &'a str,
+    tokens: &'a [Token],
+    position: usize,
+    /// The number of enclosing parentheses and unary operators.
+    depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&'a Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Result<&'a Token, ProcessShaderError> {
+        let token = self.tokens.get(self.position).ok_or_else(|| {
+            ProcessShaderError::UnexpectedEndOfIfExpression(self.expression.into())
+        })?;
+        self.position += 1;
+        Ok(token)
+    }
+
+    fn eat(&mut self, expected: &Token) -> bool {
+        if self.peek() == Some(expected) {
+            self.position += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, expected: &Token) -> Result<(), ProcessShaderError> {
+        let token = self.next()?;
+        if token == expected {
+            Ok(())
+        } else {
+            Err(self.unexpected(token))
+        }
+    }
+
+    fn unexpected(&self, token: &Token) -> ProcessShaderError {
+        let token = match token {
+            Token::Int(value) => value.to_string(),
+            Token::Ident(name) => name.clone(),
+            Token::Not => "!".into(),
+            Token::Minus => "-".into(),
+            Token::And => "&&".into(),
+            Token::Or => "||".into(),
+            Token::Eq => "==".into(),
+            Token::Ne => "!=".into(),
+            Token::Lt => "<".into(),
+            Token::Le => "<=".into(),
+            Token::Gt => ">".into(),
+            Token::Ge => ">=".into(),
+            Token::LParen => "(".into(),
+            Token::RParen => ")".into(),
+        };
+        ProcessShaderError::UnexpectedIfToken {
+            expression: self.expression.to_string(),
+            token,
+        }
+    }
+
+    /// Calls `parse` one nesting level deeper, failing past [`MAX_DEPTH`].
+    fn nested(
+        &mut self,
+        parse: impl FnOnce(&mut Self) -> Result<Expr, ProcessShaderError>,
+    ) -> Result<Expr, ProcessShaderError> {
+        if self.depth == MAX_DEPTH {
+            return Err(ProcessShaderError::IfExpressionTooDeep {
+                expression: self.expression.to_string(),
+                max_depth: MAX_DEPTH,
+            });
+        }
+        self.depth += 1;
+        let expr = parse(self);
+        self.depth -= 1;
+        expr
+    }
+
+    fn parse_or(&mut self) -> Result<Expr, ProcessShaderError> {
+        let mut exprs = vec![self.parse_and()?];
+        while self.eat(&Token::Or) {
+            exprs.push(self.parse_and()?);
+        }
+        Ok(match exprs.len() {
+            1 => exprs.pop().unwrap(),
+            _ => Expr::Or(exprs),
+        })
+    }
+
+    fn parse_and(&mut self) -> Result<Expr, ProcessShaderError> {
+        let mut exprs = vec![self.parse_comparison()?];
+        while self.eat(&Token::And) {
+            exprs.push(self.parse_comparison()?);
+        }
+        Ok(match exprs.len() {
+            1 => exprs.pop().unwrap(),
+            _ => Expr::And(exprs),
+        })
+    }
+
+    fn parse_comparison(&mut self) -> Result<Expr, ProcessShaderError> {
+        let first = self.parse_unary()?;
+        let mut rest = Vec::new();
+        while let Some(comparison) = self.peek().and_then(Comparison::from_token) {
+            self.position += 1;
+            rest.push((comparison, self.parse_unary()?));
+        }
+        Ok(match rest.is_empty() {
+            true => first,
+            false => Expr::Compare(Box::new(first), rest),
+        })
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, ProcessShaderError> {
+        if self.eat(&Token::Not) {
+            return Ok(Expr::Not(Box::new(self.nested(Self::parse_unary)?)));
+        }
+        if self.eat(&Token::Minus) {
+            return Ok(Expr::Neg(Box::new(self.nested(Self::parse_unary)?)));
+        }
+        self.parse_primary()
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr, ProcessShaderError> {
+        match self.next()? {
+            Token::Int(value) => Ok(Expr::Int(*value)),
+            Token::LParen => {
+                let expr = self.nested(Self::parse_or)?;
+                self.expect(&Token::RParen)?;
+                Ok(expr)
+            }
+            Token::Ident(name) if name == "defined" => {
+                let parenthesized = self.eat(&Token::LParen);
+                let def = match self.next()? {
+                    Token::Ident(def) => def,
+                    token => return Err(self.unexpected(token)),
+                };
+                if parenthesized {
+                    self.expect(&Token::RParen)?;
+                }
+                Ok(Expr::Defined(def.clone()))
+            }
+            Token::Ident(name) if name == "true" => Ok(Expr::Int(1)),
+            Token::Ident(name) if name == "false" => Ok(Expr::Int(0)),
+            Token::Ident(name) => Ok(Expr::Def(name.clone())),
+            token => Err(self.unexpected(token)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(expression: &str) -> bool {
+        let lookup = |name: &str| match name {
+            "BIAS" => Some(0),
+            "NEG" => Some(-2),
+            _ => None,
+        };
+        Expr::parse(expression).unwrap().evaluate(&lookup)
+    }
+
+    #[test]
+    fn precedence() {
+        assert!(eval("1 || 0 && 0"));
+        assert!(!eval("(1 || 0) && 0"));
+        assert!(eval("!0 && 1"));
+        assert!(!eval("!(0 || 1)"));
+        assert!(eval("1 < 2 == 1"));
+        assert!(eval("BIAS == 0 && !defined(NEG) || defined NEG"));
+        assert!(eval("defined(BIAS) && UNDEFINED == 0 // comment"));
+        assert!(eval("2u >= 2i && true && !false"));
+    }
+
+    #[test]
+    fn errors() {
+        let error = |expression: &str| Expr::parse(expression).unwrap_err();
+        assert!(matches!(
+            error("(1 || 0"),
+            ProcessShaderError::UnexpectedEndOfIfExpression(_)
+        ));
+        assert!(matches!(
+            error("1 1"),
+            ProcessShaderError::UnexpectedIfToken { token, .. } if token == "1"
+        ));
+        assert!(matches!(
+            error("defined(1)"),
+            ProcessShaderError::UnexpectedIfToken { token, .. } if token == "1"
+        ));
+        assert!(matches!(
+            error("A & B"),
+            ProcessShaderError::UnexpectedIfToken { token, .. } if token == "&"
+        ));
+        assert!(matches!(
+            error("3x == 3"),
+            ProcessShaderError::InvalidIfLiteral { literal, .. } if literal == "3x"
+        ));
+        assert!(matches!(
+            error(") == 1"),
+            ProcessShaderError::UnexpectedIfToken { token, .. } if token == ")"
+        ));
+    }
+
+    #[test]
+    fn unary_minus() {
+        assert!(eval("BIAS > -1"));
+        assert!(eval("-1 < BIAS"));
+        assert!(eval("NEG == -2"));
+        assert!(eval("-NEG == 2"));
+        assert!(eval("--2 == 2"));
+        assert!(eval("!-1 == 0"));
+        assert!(!eval("NEG > -2"));
+    }
+
+    #[test]
+    fn negative_literals_are_referenced() {
+        assert_eq!(
+            Expr::parse("BIAS > -1 && -3 == NEG").unwrap().references(),
+            vec![
+                ("BIAS".to_string(), Some((Comparison::Gt, -1))),
+                ("NEG".to_string(), Some((Comparison::Eq, -3)))
+            ]
+        );
+    }
+
+    #[test]
+    fn reversed_comparisons_are_flipped() {
+        assert_eq!(
+            Expr::parse("4 < TAPS || 2 >= TAPS || (TAPS)")
+                .unwrap()
+                .references(),
+            vec![
+                ("TAPS".to_string(), Some((Comparison::Gt, 4))),
+                ("TAPS".to_string(), Some((Comparison::Le, 2))),
+                ("TAPS".to_string(), None)
+            ]
+        );
+    }
+
+    #[test]
+    fn nesting_is_limited() {
+        let nested = |depth: usize, open: &str, close: &str| {
+            format!("{}1{}", open.repeat(depth), close.repeat(depth))
+        };
+        assert!(eval(&nested(MAX_DEPTH, "(", ")")));
+        assert!(matches!(
+            Expr::parse(&nested(MAX_DEPTH + 1, "(", ")")),
+            Err(ProcessShaderError::IfExpressionTooDeep {
+                max_depth: MAX_DEPTH,
+                ..
+            })
+        ));
+        assert!(matches!(
+            Expr::parse(&nested(5000, "!", "")),
+            Err(ProcessShaderError::IfExpressionTooDeep { .. })
+        ));
+        assert!(matches!(
+            Expr::parse(&nested(5000, "(-", ")")),
+            Err(ProcessShaderError::IfExpressionTooDeep { .. })
+        ));
+        // Chains of binary operators don't nest.
+        assert!(eval(&vec!["1 == 1"; 100_000].join(" && ")));
+    }
+
+    #[test]
+    fn minus_is_not_binary() {
+        assert!(matches!(
+            Expr::parse("BIAS - 1"),
+            Err(ProcessShaderError::UnexpectedIfToken { token, .. }) if token == "-"
+        ));
+        assert!(matches!(
+            Expr::parse("BIAS > -"),
+            Err(ProcessShaderError::UnexpectedEndOfIfExpression(_))
+        ));
+    }
+}
@@ -0,0 +1,317 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex, MutexGuard,
+    },
+};
+
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use thiserror::Error;
+
+use super::{
+    normalize_shader_defs, ProcessedShader, ShaderDefVal, ShaderHandle, ShaderImport,
+    ShaderLibrary, ShaderLoadError, ShaderLoader,
+};
+
+#[derive(Debug, Error)]
+pub enum ShaderWatchError {
+    #[error(transparent)]
+    Notify(#[from] notify::Error),
+    #[error(transparent)]
+    Load(#[from] ShaderLoadError),
+}
+
+/// The outcome of reprocessing a watched shader after a file it depends on changed.
+#[derive(Debug)]
+pub struct ShaderReload {
+    /// The watched shader that was reprocessed. If a changed file couldn't be reloaded, this is the
+    /// handle of that file instead and `result` holds the error.
+    pub handle: ShaderHandle,
+    /// The shader defs the shader was reprocessed with, normalized. Empty if a changed file
+    /// couldn't be reloaded.
+    pub shader_defs: Vec<ShaderDefVal>,
+    pub result: Result<ProcessedShader, ShaderLoadError>,
+}
+
+/// Watches the roots of a [`ShaderLoader`] and reprocesses shaders when files they depend on
+/// change.
+///
+/// When a file changes it is reloaded into the [`ShaderLibrary`], and every watched shader that
+/// imports it, directly or through other imports, is processed again with each set of shader defs
+/// it was watched with. The results are sent to the [`Receiver`] returned by [`ShaderHotReloader::new`].
+pub struct ShaderHotReloader {
+    state: Arc<Mutex<HotReloadState>>,
+    _watcher: RecommendedWatcher,
+}
+
+struct HotReloadState {
+    loader: ShaderLoader,
+    library: ShaderLibrary,
+    /// The loader's roots, canonicalized to match the paths in file events.
+    roots: Vec<PathBuf>,
+    /// The normalized def sets each watched shader is processed with, in the order they were
+    /// watched.
+    watched: HashMap<ShaderHandle, Vec<Vec<ShaderDefVal>>>,
+    sender: Sender<ShaderReload>,
+}
+
+impl ShaderHotReloader {
+    pub fn new(
+        loader: ShaderLoader,
+        library: ShaderLibrary,
+    ) -> Result<(Self, Receiver<ShaderReload>), ShaderWatchError> {
+        let roots = loader
+            .roots()
+            .iter()
+            .map(|root| {
+                root.canonicalize().map_err(|source| ShaderLoadError::Io {
+                    path: root.clone(),
+                    source,
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let (sender, receiver) = mpsc::channel();
+        let state = Arc::new(Mutex::new(HotReloadState {
+            loader,
+            library,
+            roots: roots.clone(),
+            watched: HashMap::new(),
+            sender,
+        }));
+
+        let handler_state = state.clone();
+        let mut watcher =
+            notify::recommended_watcher(move |event: notify::Result<notify::Event>| match event {
+                Ok(event) if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) => {
+                    handler_state.lock().unwrap().files_changed(&event.paths);
+                }
+                Ok(_) => {}
+                Err(err) => tracing::warn!("Shader file watcher error: {}", err),
+            })?;
+        for root in &roots {
+            watcher.watch(root, RecursiveMode::Recursive)?;
+        }
+
+        Ok((
+            Self {
+                state,
+                _watcher: watcher,
+            },
+            receiver,
+        ))
+    }
+
+    /// Loads the shader at `asset_path` and its imports, and reprocesses it with `shader_defs`
+    /// whenever one of their files changes. Watching the same shader with other defs reprocesses
+    /// it with each of them.
+    pub fn watch(
+        &self,
+        asset_path: &str,
+        shader_defs: &[ShaderDefVal],
+    ) -> Result<ShaderHandle, ShaderLoadError> {
+        self.lock().watch(asset_path, shader_defs)
+    }
+
+    /// Stops reprocessing the shader stored under `handle` with any defs. It stays in the library.
+    pub fn unwatch(&self, handle: ShaderHandle) -> bool {
+        self.lock().watched.remove(&handle).is_some()
+    }
+
+    /// Stops reprocessing the shader stored under `handle` with `shader_defs`, but keeps the other
+    /// def sets it's watched with.
+    pub fn unwatch_defs(&self, handle: ShaderHandle, shader_defs: &[ShaderDefVal]) -> bool {
+        let mut state = self.lock();
+        let def_sets = match state.watched.get_mut(&handle) {
+            Some(def_sets) => def_sets,
+            None => return false,
+        };
+        let shader_defs = normalize_shader_defs(shader_defs);
+        let watched = def_sets.len();
+        def_sets.retain(|watched| *watched != shader_defs);
+        let removed = def_sets.len() != watched;
+        if def_sets.is_empty() {
+            state.watched.remove(&handle);
+        }
+        removed
+    }
+
+    /// Processes the shader stored under `handle` with `shader_defs`, from the library the watched
+    /// shaders are loaded into.
+    pub fn process(
+        &self,
+        handle: ShaderHandle,
+        shader_defs: &[ShaderDefVal],
+    ) -> Result<ProcessedShader, ShaderLoadError> {
+        Ok(self.lock().library.process(handle, shader_defs)?)
+    }
+
+    /// Runs `f` with the library the watched shaders are loaded into.
+    pub fn with_library<R>(&self, f: impl FnOnce(&mut ShaderLibrary) -> R) -> R {
+        f(&mut self.lock().library)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HotReloadState> {
+        self.state.lock().unwrap()
+    }
+}
+
+impl HotReloadState {
+    fn watch(
+        &mut self,
+        asset_path: &str,
+        shader_defs: &[ShaderDefVal],
+    ) -> Result<ShaderHandle, ShaderLoadError> {
+        let handle = self.loader.load(&mut self.library, asset_path)?;
+        let shader_defs = normalize_shader_defs(shader_defs);
+        let def_sets = self.watched.entry(handle).or_default();
+        if !def_sets.contains(&shader_defs) {
+            def_sets.push(shader_defs);
+        }
+        Ok(handle)
+    }
+
+    fn files_changed(&mut self, paths: &[PathBuf]) {
+        let mut changed = HashSet::new();
+        for path in paths {
+            let asset_path = match self.asset_path(path) {
+                Some(asset_path) => asset_path,
+                None => continue,
+            };
+            match self.reload(&asset_path) {
+                Ok(Some(handle)) => {
+                    changed.insert(handle);
+                }
+                Ok(None) => {}
+                Err(err) => {
+                    let import = ShaderImport::AssetPath(asset_path);
+                    if let Some(handle) = self.library.get_handle(&import) {
+                        let _ = self.sender.send(ShaderReload {
+                            handle,
+                            shader_defs: Vec::new(),
+                            result: Err(err),
+                        });
+                    }
+                }
+            }
+        }
+        if changed.is_empty() {
+            return;
+        }
+
+        for (&handle, def_sets) in &self.watched {
+            let affected = changed.contains(&handle)
+                || self
+                    .library
+                    .dependencies(handle)
+                    .iter()
+                    .any(|dependency| changed.contains(dependency));
+            if !affected {
+                continue;
+            }
+            for shader_defs in def_sets {
+                let result = self
+                    .library
+                    .process(handle, shader_defs)
+                    .map_err(ShaderLoadError::from);
+                // The receiver may have been dropped, in which case nobody is interested anymore.
+                let _ = self.sender.send(ShaderReload {
+                    handle,
+                    shader_defs: shader_defs.clone(),
+                    result,
+                });
+            }
+        }
+    }
+
+    /// The asset path of the file at `path`, if it is in one of the roots.
+    fn asset_path(&self, path: &Path) -> Option<String> {
+        let relative = self
+            .roots
+            .iter()
+            .find_map(|root| path.strip_prefix(root).ok())?;
+        let components = relative
+            .components()
+            .map(|component| component.as_os_str().to_str())
+            .collect::<Option<Vec<_>>>()?;
+        Some(components.join("/"))
+    }
+
+    /// Reloads the file at `asset_path` into the library, along with any imports it now needs.
+    /// Returns the handle of the reloaded shader, or `None` if nothing depends on the file.
+    fn reload(&mut self, asset_path: &str) -> Result<Option<ShaderHandle>, ShaderLoadError> {
+        let import = ShaderImport::AssetPath(asset_path.to_string());
+        if let Some(handle) = self.library.get_handle(&import) {
+            let file = self
+                .loader
+                .find(asset_path)?
+                .ok_or_else(|| ShaderLoadError::NotFound(asset_path.to_string()))?;
+            let shader = self.loader.read_checked(&file)?.with_asset_path(asset_path);
+            self.library.replace(handle, shader)?;
+            self.loader.load_imports(&mut self.library, handle)?;
+            return Ok(Some(handle));
+        }
+
+        // A new file may be an import that couldn't be found before.
+        let handles = self
+            .library
+            .iter()
+            .map(|(handle, _)| handle)
+            .collect::<Vec<_>>();
+        for handle in handles {
+            self.loader.load_imports(&mut self.library, handle)?;
+        }
+        Ok(self.library.get_handle(&import))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, process};
+
+    use super::*;
+
+    #[test]
+    fn each_watched_def_set_is_reprocessed() {
+        let root = std::env::temp_dir().join(format!("hot-reload-{}", process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        fs::write(
+            root.join("main.wgsl"),
+            "#import \"common.wgsl\"\n#ifdef A\nfn a() {}\n#endif",
+        )
+        .unwrap();
+        fs::write(root.join("common.wgsl"), "fn common() {}").unwrap();
+
+        // Events are fed to the state directly, instead of through a file watcher.
+        let (sender, receiver) = mpsc::channel();
+        let mut state = HotReloadState {
+            loader: ShaderLoader::new(&root),
+            library: ShaderLibrary::default(),
+            roots: vec![root.canonicalize().unwrap()],
+            watched: HashMap::new(),
+            sender,
+        };
+        let a = vec![ShaderDefVal::from("A")];
+        let handle = state.watch("main.wgsl", &[]).unwrap();
+        assert_eq!(state.watch("main.wgsl", &a).unwrap(), handle);
+        assert_eq!(state.watch("main.wgsl", &[]).unwrap(), handle);
+        assert_eq!(state.watched[&handle].len(), 2);
+
+        fs::write(root.join("common.wgsl"), "fn common2() {}").unwrap();
+        state.files_changed(&[state.roots[0].join("common.wgsl")]);
+        let reloads = receiver.try_iter().collect::<Vec<_>>();
+        fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(reloads.len(), 2);
+        for (reload, shader_defs) in reloads.iter().zip([vec![], a]) {
+            assert_eq!(reload.handle, handle);
+            assert_eq!(reload.shader_defs, shader_defs);
+            let processed = reload.result.as_ref().unwrap();
+            let source = processed.get_wgsl_source().unwrap();
+            assert!(source.contains("fn common2()"));
+            assert_eq!(source.contains("fn a()"), !shader_defs.is_empty());
+        }
+    }
+}
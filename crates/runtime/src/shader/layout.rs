@@ -0,0 +1,526 @@
+use std::{collections::BTreeMap, num::NonZeroU64};
+
+use naga::{
+    GlobalVariable, ImageClass, ImageDimension, ScalarKind, ShaderStage, StorageAccess,
+    StorageClass, StorageFormat, TypeInner,
+};
+use thiserror::Error;
+
+use super::ShaderReflection;
+
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum BindGroupLayoutError {
+    #[error(
+        "The global '{name}' at group {group}, binding {binding} has a type that can't be bound."
+    )]
+    UnsupportedBinding {
+        name: String,
+        group: u32,
+        binding: u32,
+    },
+    #[error("Group {group}, binding {binding} is declared as {first:?} and as {second:?}.")]
+    Conflict {
+        group: u32,
+        binding: u32,
+        first: wgpu::BindingType,
+        second: wgpu::BindingType,
+    },
+}
+
+/// The bind group layout entries used by one or more shader modules, by group and binding.
+///
+/// Some properties can't be seen in a shader, so they're filled in the way most shaders use them:
+/// buffers have no dynamic offset, float textures are filterable and non-comparison samplers are
+/// filtering. Adjust the entries where that doesn't hold.
+///
+/// ```ignore
+/// let mut layouts = vertex.reflect()?.bind_group_layouts()?;
+/// layouts.merge(&fragment.reflect()?.bind_group_layouts()?)?;
+/// let bind_group_layouts = (0..layouts.group_count())
+///     .map(|group| {
+///         device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+///             label: None,
+///             entries: &layouts.entries(group),
+///         })
+///     })
+///     .collect::<Vec<_>>();
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BindGroupLayouts {
+    groups: BTreeMap<u32, BTreeMap<u32, wgpu::BindGroupLayoutEntry>>,
+}
+
+impl BindGroupLayouts {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `entry` to `group`. If the binding is already used, the visibilities are combined, and
+    /// the binding types must match apart from the minimum buffer size, of which the larger is kept.
+    pub fn insert(
+        &mut self,
+        group: u32,
+        entry: wgpu::BindGroupLayoutEntry,
+    ) -> Result<(), BindGroupLayoutError> {
+        let entries = self.groups.entry(group).or_default();
+        let existing = match entries.get_mut(&entry.binding) {
+            Some(existing) => existing,
+            None => {
+                entries.insert(entry.binding, entry);
+                return Ok(());
+            }
+        };
+        let conflict = || BindGroupLayoutError::Conflict {
+            group,
+            binding: entry.binding,
+            first: existing.ty,
+            second: entry.ty,
+        };
+        if existing.count != entry.count {
+            return Err(conflict());
+        }
+        let ty = merge_binding_types(existing.ty, entry.ty).ok_or_else(conflict)?;
+        existing.ty = ty;
+        existing.visibility |= entry.visibility;
+        Ok(())
+    }
+
+    /// Adds the entries of `other`, for example the layouts of a fragment shader in a different
+    /// module than the vertex shader.
+    pub fn merge(&mut self, other: &BindGroupLayouts) -> Result<(), BindGroupLayoutError> {
+        for (&group, entries) in &other.groups {
+            for entry in entries.values() {
+                self.insert(group, *entry)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The entries of `group`, sorted by binding. Empty if the group isn't used.
+    pub fn entries(&self, group: u32) -> Vec<wgpu::BindGroupLayoutEntry> {
+        self.groups
+            .get(&group)
+            .map(|entries| entries.values().copied().collect())
+            .unwrap_or_default()
+    }
+
+    /// The used groups and their entries, sorted by group and binding.
+    pub fn iter(&self) -> impl Iterator<Item = (u32, Vec<wgpu::BindGroupLayoutEntry>)> + '_ {
+        self.groups
+            .iter()
+            .map(|(&group, entries)| (group, entries.values().copied().collect()))
+    }
+
+    /// How many bind group layouts a pipeline layout needs: one more than the highest used group.
+    /// Groups below it that aren't used get empty layouts.
+    pub fn group_count(&self) -> u32 {
+        self.groups.keys().next_back().map_or(0, |&group| group + 1)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.groups.is_empty()
+    }
+}
+
+impl ShaderReflection {
+    /// The bind group layout entries of the module's resource bindings. Each entry is visible to
+    /// the stages of the entry points that use it, directly or through the functions they call.
+    /// Bindings no entry point uses are left out.
+    pub fn bind_group_layouts(&self) -> Result<BindGroupLayouts, BindGroupLayoutError> {
+        let mut layouts = BindGroupLayouts::new();
+        for (handle, global) in self.module.global_variables.iter() {
+            let resource_binding = match &global.binding {
+                Some(resource_binding) => resource_binding,
+                None => continue,
+            };
+            let visibility = self
+                .module
+                .entry_points
+                .iter()
+                .enumerate()
+                .filter(|&(index, _)| !self.module_info.get_entry_point(index)[handle].is_empty())
+                .fold(
+                    wgpu::ShaderStages::empty(),
+                    |visibility, (_, entry_point)| visibility | shader_stages(entry_point.stage),
+                );
+            if visibility.is_empty() {
+                continue;
+            }
+            let ty = self.binding_type(global).ok_or_else(|| {
+                BindGroupLayoutError::UnsupportedBinding {
+                    name: global.name.clone().unwrap_or_default(),
+                    group: resource_binding.group,
+                    binding: resource_binding.binding,
+                }
+            })?;
+            layouts.insert(
+                resource_binding.group,
+                wgpu::BindGroupLayoutEntry {
+                    binding: resource_binding.binding,
+                    visibility,
+                    ty,
+                    count: None,
+                },
+            )?;
+        }
+        Ok(layouts)
+    }
+
+    fn binding_type(&self, global: &GlobalVariable) -> Option<wgpu::BindingType> {
+        let inner = &self.module.types[global.ty].inner;
+        let min_binding_size = || NonZeroU64::new(inner.span(&self.module.constants) as u64);
+        match global.class {
+            StorageClass::Uniform => Some(wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Uniform,
+                has_dynamic_offset: false,
+                min_binding_size: min_binding_size(),
+            }),
+            StorageClass::Storage { access } => Some(wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Storage {
+                    read_only: !access.contains(StorageAccess::STORE),
+                },
+                has_dynamic_offset: false,
+                min_binding_size: min_binding_size(),
+            }),
+            StorageClass::Handle => match *inner {
+                TypeInner::Sampler { comparison } => {
+                    Some(wgpu::BindingType::Sampler(if comparison {
+                        wgpu::SamplerBindingType::Comparison
+                    } else {
+                        wgpu::SamplerBindingType::Filtering
+                    }))
+                }
+                TypeInner::Image {
+                    dim,
+                    arrayed,
+                    class,
+                } => {
+                    let view_dimension = view_dimension(dim, arrayed)?;
+                    match class {
+                        ImageClass::Sampled { kind, multi } => Some(wgpu::BindingType::Texture {
+                            sample_type: match kind {
+                                ScalarKind::Float => {
+                                    wgpu::TextureSampleType::Float { filterable: true }
+                                }
+                                ScalarKind::Sint => wgpu::TextureSampleType::Sint,
+                                ScalarKind::Uint => wgpu::TextureSampleType::Uint,
+                                ScalarKind::Bool => return None,
+                            },
+                            view_dimension,
+                            multisampled: multi,
+                        }),
+                        ImageClass::Depth { multi } => Some(wgpu::BindingType::Texture {
+                            sample_type: wgpu::TextureSampleType::Depth,
+                            view_dimension,
+                            multisampled: multi,
+                        }),
+                        ImageClass::Storage { format, access } => {
+                            Some(wgpu::BindingType::StorageTexture {
+                                access: storage_texture_access(access)?,
+                                format: texture_format(format),
+                                view_dimension,
+                            })
+                        }
+                    }
+                }
+                _ => None,
+            },
+            StorageClass::Function
+            | StorageClass::Private
+            | StorageClass::WorkGroup
+            | StorageClass::PushConstant => None,
+        }
+    }
+}
+
+/// The binding type that satisfies both `a` and `b`, if they only differ in their minimum buffer
+/// size.
+fn merge_binding_types(a: wgpu::BindingType, b: wgpu::BindingType) -> Option<wgpu::BindingType> {
+    match (a, b) {
+        (
+            wgpu::BindingType::Buffer {
+                ty,
+                has_dynamic_offset,
+                min_binding_size: a_size,
+            },
+            wgpu::BindingType::Buffer {
+                ty: b_ty,
+                has_dynamic_offset: b_has_dynamic_offset,
+                min_binding_size: b_size,
+            },
+        ) if ty == b_ty && has_dynamic_offset == b_has_dynamic_offset => {
+            Some(wgpu::BindingType::Buffer {
+                ty,
+                has_dynamic_offset,
+                min_binding_size: a_size.max(b_size),
+            })
+        }
+        (a, b) if a == b => Some(a),
+        _ => None,
+    }
+}
+
+fn shader_stages(stage: ShaderStage) -> wgpu::ShaderStages {
+    match stage {
+        ShaderStage::Vertex => wgpu::ShaderStages::VERTEX,
+        ShaderStage::Fragment => wgpu::ShaderStages::FRAGMENT,
+        ShaderStage::Compute => wgpu::ShaderStages::COMPUTE,
+    }
+}
+
+fn view_dimension(dim: ImageDimension, arrayed: bool) -> Option<wgpu::TextureViewDimension> {
+    match (dim, arrayed) {
+        (ImageDimension::D1, false) => Some(wgpu::TextureViewDimension::D1),
+        (ImageDimension::D2, false) => Some(wgpu::TextureViewDimension::D2),
+        (ImageDimension::D2, true) => Some(wgpu::TextureViewDimension::D2Array),
+        (ImageDimension::D3, false) => Some(wgpu::TextureViewDimension::D3),
+        (ImageDimension::Cube, false) => Some(wgpu::TextureViewDimension::Cube),
+        (ImageDimension::Cube, true) => Some(wgpu::TextureViewDimension::CubeArray),
+        (ImageDimension::D1 | ImageDimension::D3, true) => None,
+    }
+}
+
+fn storage_texture_access(access: StorageAccess) -> Option<wgpu::StorageTextureAccess> {
+    match (
+        access.contains(StorageAccess::LOAD),
+        access.contains(StorageAccess::STORE),
+    ) {
+        (true, true) => Some(wgpu::StorageTextureAccess::ReadWrite),
+        (true, false) => Some(wgpu::StorageTextureAccess::ReadOnly),
+        (false, true) => Some(wgpu::StorageTextureAccess::WriteOnly),
+        (false, false) => None,
+    }
+}
+
+fn texture_format(format: StorageFormat) -> wgpu::TextureFormat {
+    match format {
+        StorageFormat::R8Unorm => wgpu::TextureFormat::R8Unorm,
+        StorageFormat::R8Snorm => wgpu::TextureFormat::R8Snorm,
+        StorageFormat::R8Uint => wgpu::TextureFormat::R8Uint,
+        StorageFormat::R8Sint => wgpu::TextureFormat::R8Sint,
+        StorageFormat::R16Uint => wgpu::TextureFormat::R16Uint,
+        StorageFormat::R16Sint => wgpu::TextureFormat::R16Sint,
+        StorageFormat::R16Float => wgpu::TextureFormat::R16Float,
+        StorageFormat::Rg8Unorm => wgpu::TextureFormat::Rg8Unorm,
+        StorageFormat::Rg8Snorm => wgpu::TextureFormat::Rg8Snorm,
+        StorageFormat::Rg8Uint => wgpu::TextureFormat::Rg8Uint,
+        StorageFormat::Rg8Sint => wgpu::TextureFormat::Rg8Sint,
+        StorageFormat::R32Uint => wgpu::TextureFormat::R32Uint,
+        StorageFormat::R32Sint => wgpu::TextureFormat::R32Sint,
+        StorageFormat::R32Float => wgpu::TextureFormat::R32Float,
+        StorageFormat::Rg16Uint => wgpu::TextureFormat::Rg16Uint,
+        StorageFormat::Rg16Sint => wgpu::TextureFormat::Rg16Sint,
+        StorageFormat::Rg16Float => wgpu::TextureFormat::Rg16Float,
+        StorageFormat::Rgba8Unorm => wgpu::TextureFormat::Rgba8Unorm,
+        StorageFormat::Rgba8Snorm => wgpu::TextureFormat::Rgba8Snorm,
+        StorageFormat::Rgba8Uint => wgpu::TextureFormat::Rgba8Uint,
+        StorageFormat::Rgba8Sint => wgpu::TextureFormat::Rgba8Sint,
+        StorageFormat::Rgb10a2Unorm => wgpu::TextureFormat::Rgb10a2Unorm,
+        StorageFormat::Rg11b10Float => wgpu::TextureFormat::Rg11b10Float,
+        StorageFormat::Rg32Uint => wgpu::TextureFormat::Rg32Uint,
+        StorageFormat::Rg32Sint => wgpu::TextureFormat::Rg32Sint,
+        StorageFormat::Rg32Float => wgpu::TextureFormat::Rg32Float,
+        StorageFormat::Rgba16Uint => wgpu::TextureFormat::Rgba16Uint,
+        StorageFormat::Rgba16Sint => wgpu::TextureFormat::Rgba16Sint,
+        StorageFormat::Rgba16Float => wgpu::TextureFormat::Rgba16Float,
+        StorageFormat::Rgba32Uint => wgpu::TextureFormat::Rgba32Uint,
+        StorageFormat::Rgba32Sint => wgpu::TextureFormat::Rgba32Sint,
+        StorageFormat::Rgba32Float => wgpu::TextureFormat::Rgba32Float,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::super::{Shader, ShaderImport, ShaderProcessor};
+    use super::*;
+
+    fn reflect(source: &str) -> ShaderReflection {
+        ShaderProcessor::default()
+            .process_with_provider(
+                &Shader::from_wgsl(source.to_string()),
+                &[],
+                &HashMap::<ShaderImport, Shader>::new(),
+            )
+            .unwrap()
+            .reflect()
+            .unwrap()
+    }
+
+    fn buffer(
+        binding: u32,
+        visibility: wgpu::ShaderStages,
+        ty: wgpu::BufferBindingType,
+        size: u64,
+    ) -> wgpu::BindGroupLayoutEntry {
+        wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility,
+            ty: wgpu::BindingType::Buffer {
+                ty,
+                has_dynamic_offset: false,
+                min_binding_size: NonZeroU64::new(size),
+            },
+            count: None,
+        }
+    }
+
+    fn entry(binding: u32, ty: wgpu::BindingType) -> wgpu::BindGroupLayoutEntry {
+        wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty,
+            count: None,
+        }
+    }
+
+    const CAMERA: &str = "
+        struct Camera { view_proj: mat4x4<f32>; };
+        [[group(0), binding(0)]] var<uniform> camera: Camera;
+        [[stage(vertex)]]
+        fn vs_main([[location(0)]] position: vec3<f32>) -> [[builtin(position)]] vec4<f32> {
+            return camera.view_proj * vec4<f32>(position, 1.0);
+        }
+    ";
+
+    #[test]
+    fn entries_are_reflected_per_stage() {
+        let layouts = reflect(
+            "
+            struct Camera { view_proj: mat4x4<f32>; };
+            struct Lights { count: u32; };
+            struct Counter { value: atomic<u32>; };
+            [[group(0), binding(0)]] var<uniform> camera: Camera;
+            [[group(0), binding(1)]] var<storage, read> lights: Lights;
+            [[group(0), binding(2)]] var<storage, read_write> counter: Counter;
+            [[group(1), binding(0)]] var color_texture: texture_2d<f32>;
+            [[group(1), binding(1)]] var color_sampler: sampler;
+            [[group(1), binding(2)]] var shadow_map: texture_depth_2d;
+            [[group(1), binding(3)]] var shadow_sampler: sampler_comparison;
+            [[group(2), binding(0)]] var<uniform> unused: Camera;
+
+            [[stage(vertex)]]
+            fn vs_main([[location(0)]] position: vec3<f32>) -> [[builtin(position)]] vec4<f32> {
+                return camera.view_proj * vec4<f32>(position, f32(lights.count));
+            }
+
+            fn shadow() -> f32 {
+                return textureSampleCompare(shadow_map, shadow_sampler, vec2<f32>(0.5, 0.5), 0.5);
+            }
+
+            [[stage(fragment)]]
+            fn fs_main() -> [[location(0)]] vec4<f32> {
+                let previous = atomicAdd(&counter.value, 1u);
+                let color = textureSample(color_texture, color_sampler, vec2<f32>(0.5, 0.5));
+                return color * f32(lights.count) * shadow();
+            }
+            ",
+        )
+        .bind_group_layouts()
+        .unwrap();
+
+        assert_eq!(layouts.group_count(), 2);
+        assert_eq!(
+            layouts.entries(0),
+            vec![
+                buffer(
+                    0,
+                    wgpu::ShaderStages::VERTEX,
+                    wgpu::BufferBindingType::Uniform,
+                    64
+                ),
+                buffer(
+                    1,
+                    wgpu::ShaderStages::VERTEX_FRAGMENT,
+                    wgpu::BufferBindingType::Storage { read_only: true },
+                    4
+                ),
+                buffer(
+                    2,
+                    wgpu::ShaderStages::FRAGMENT,
+                    wgpu::BufferBindingType::Storage { read_only: false },
+                    4
+                ),
+            ]
+        );
+        assert_eq!(
+            layouts.entries(1),
+            vec![
+                entry(
+                    0,
+                    wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    }
+                ),
+                entry(
+                    1,
+                    wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering)
+                ),
+                entry(
+                    2,
+                    wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Depth,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    }
+                ),
+                entry(
+                    3,
+                    wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Comparison)
+                ),
+            ]
+        );
+        assert!(layouts.entries(2).is_empty());
+    }
+
+    #[test]
+    fn merged_modules_combine_visibility_and_detect_conflicts() {
+        let mut layouts = reflect(CAMERA).bind_group_layouts().unwrap();
+        let fragment = reflect(
+            "
+            struct Tint { color: vec4<f32>; };
+            [[group(0), binding(0)]] var<uniform> tint: Tint;
+            [[stage(fragment)]]
+            fn fs_main() -> [[location(0)]] vec4<f32> {
+                return tint.color;
+            }
+            ",
+        )
+        .bind_group_layouts()
+        .unwrap();
+        layouts.merge(&fragment).unwrap();
+        assert_eq!(
+            layouts.entries(0),
+            vec![buffer(
+                0,
+                wgpu::ShaderStages::VERTEX_FRAGMENT,
+                wgpu::BufferBindingType::Uniform,
+                64
+            )]
+        );
+
+        let texture = reflect(
+            "
+            [[group(0), binding(0)]] var color_texture: texture_2d<f32>;
+            [[stage(fragment)]]
+            fn fs_main() -> [[location(0)]] vec4<f32> {
+                return textureLoad(color_texture, vec2<i32>(0, 0), 0);
+            }
+            ",
+        )
+        .bind_group_layouts()
+        .unwrap();
+        assert_eq!(
+            layouts.merge(&texture),
+            Err(BindGroupLayoutError::Conflict {
+                group: 0,
+                binding: 0,
+                first: layouts.entries(0)[0].ty,
+                second: texture.entries(0)[0].ty,
+            })
+        );
+    }
+}
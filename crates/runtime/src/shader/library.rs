@@ -0,0 +1,458 @@
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+};
+
+use thiserror::Error;
+
+use super::{
+    normalize_shader_defs, ProcessShaderError, ProcessedShader, Shader, ShaderDefVal, ShaderHandle,
+    ShaderImport, ShaderProcessor, ShaderSourceProvider,
+};
+
+#[derive(Debug, Error)]
+pub enum ShaderLibraryError {
+    #[error("The import path {import:?} is already registered by shader {existing:?}.")]
+    DuplicateImportPath {
+        import: ShaderImport,
+        existing: ShaderHandle,
+    },
+    #[error("No shader is registered for handle {0:?}.")]
+    UnknownShader(ShaderHandle),
+    #[error(transparent)]
+    Process(#[from] ProcessShaderError),
+}
+
+/// Owns a set of shaders and the import paths they can be imported by, and processes them with a
+/// [`ShaderProcessor`].
+///
+/// A shader's [`import_path`](Shader::import_path) and [`asset_path`](Shader::asset_path) are
+/// registered when it is added, so other shaders in the library can `#import` it.
+///
+/// Processed shaders are cached per handle and def set. Adding, replacing or removing a shader only
+/// invalidates the cached shaders that import it, directly or through other imports.
+#[derive(Default)]
+pub struct ShaderLibrary {
+    processor: ShaderProcessor,
+    shaders: HashMap<ShaderHandle, Shader>,
+    import_handles: HashMap<ShaderImport, ShaderHandle>,
+    cache: Mutex<ProcessedCache>,
+}
+
+/// Counters of the processed shader cache of a [`ShaderLibrary`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ShaderCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// The number of processed shaders currently cached.
+    pub entries: usize,
+}
+
+#[derive(Default)]
+struct ProcessedCache {
+    /// Keyed by the processed shader and its normalized defs.
+    entries: HashMap<(ShaderHandle, Vec<ShaderDefVal>), CachedShader>,
+    hits: u64,
+    misses: u64,
+}
+
+struct CachedShader {
+    processed: ProcessedShader,
+    /// The shaders the processed shader imports, directly or through other imports.
+    dependencies: HashSet<ShaderHandle>,
+}
+
+impl ShaderLibrary {
+    pub fn new(processor: ShaderProcessor) -> Self {
+        Self {
+            processor,
+            ..Default::default()
+        }
+    }
+
+    #[inline]
+    pub fn processor(&self) -> &ShaderProcessor {
+        &self.processor
+    }
+
+    /// The processor settings affect every processed shader, so this clears the cache.
+    #[inline]
+    pub fn processor_mut(&mut self) -> &mut ShaderProcessor {
+        self.clear_cache();
+        &mut self.processor
+    }
+
+    pub fn cache_stats(&self) -> ShaderCacheStats {
+        let cache = self.cache.lock().unwrap();
+        ShaderCacheStats {
+            hits: cache.hits,
+            misses: cache.misses,
+            entries: cache.entries.len(),
+        }
+    }
+
+    /// Removes all cached processed shaders. The hit and miss counters are kept.
+    pub fn clear_cache(&mut self) {
+        self.cache.get_mut().unwrap().entries.clear();
+    }
+
+    /// Adds `shader` under a new handle. Fails if another shader already uses its import path, or
+    /// if its import path disagrees with its `#define_import_path`.
+    pub fn add(&mut self, shader: Shader) -> Result<ShaderHandle, ShaderLibraryError> {
+        shader.validate_import_path()?;
+        let handle = ShaderHandle::new();
+        self.register_import_path(handle, &shader)?;
+        self.shaders.insert(handle, shader);
+        // The new shader may shadow an asset path that cached shaders resolved to another file.
+        self.invalidate(handle);
+        Ok(handle)
+    }
+
+    /// Replaces the shader stored under `handle`, returning the previous one. Its import path is
+    /// updated to the one of the new shader.
+    pub fn replace(
+        &mut self,
+        handle: ShaderHandle,
+        shader: Shader,
+    ) -> Result<Shader, ShaderLibraryError> {
+        if !self.shaders.contains_key(&handle) {
+            return Err(ShaderLibraryError::UnknownShader(handle));
+        }
+        shader.validate_import_path()?;
+        self.register_import_path(handle, &shader)?;
+        let new_imports = import_keys(&shader).collect::<Vec<_>>();
+        let previous = self.shaders.insert(handle, shader).unwrap();
+        for import in import_keys(&previous) {
+            if !new_imports.contains(&import) {
+                self.import_handles.remove(&import);
+            }
+        }
+        self.invalidate(handle);
+        Ok(previous)
+    }
+
+    /// Removes the shader stored under `handle` along with its import paths.
+    pub fn remove(&mut self, handle: ShaderHandle) -> Option<Shader> {
+        let shader = self.shaders.remove(&handle)?;
+        for import in import_keys(&shader) {
+            self.import_handles.remove(&import);
+        }
+        self.invalidate(handle);
+        Some(shader)
+    }
+
+    #[inline]
+    pub fn get(&self, handle: ShaderHandle) -> Option<&Shader> {
+        self.shaders.get(&handle)
+    }
+
+    #[inline]
+    pub fn contains(&self, handle: ShaderHandle) -> bool {
+        self.shaders.contains_key(&handle)
+    }
+
+    /// The handle of the shader registered under `import`, if any.
+    #[inline]
+    pub fn get_handle(&self, import: &ShaderImport) -> Option<ShaderHandle> {
+        self.import_handles.get(import).copied()
+    }
+
+    /// The shader registered under `import`, if any.
+    pub fn get_by_import(&self, import: &ShaderImport) -> Option<&Shader> {
+        self.get_handle(import).and_then(|handle| self.get(handle))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (ShaderHandle, &Shader)> {
+        self.shaders
+            .iter()
+            .map(|(handle, shader)| (*handle, shader))
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.shaders.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.shaders.is_empty()
+    }
+
+    /// Processes the shader stored under `handle`, resolving its imports from this library. The
+    /// result is cached, so processing the same shader with the same defs again is cheap.
+    pub fn process(
+        &self,
+        handle: ShaderHandle,
+        shader_defs: &[ShaderDefVal],
+    ) -> Result<ProcessedShader, ShaderLibraryError> {
+        let shader = self
+            .get(handle)
+            .ok_or(ShaderLibraryError::UnknownShader(handle))?;
+        let key = (handle, normalize_shader_defs(shader_defs));
+        {
+            let mut cache = self.cache.lock().unwrap();
+            if let Some(cached) = cache.entries.get(&key) {
+                let processed = cached.processed.clone();
+                cache.hits += 1;
+                return Ok(processed);
+            }
+            cache.misses += 1;
+        }
+
+        let processed = self.processor.process_with_provider(shader, &key.1, self)?;
+        let cached = CachedShader {
+            processed: processed.clone(),
+            dependencies: self.dependencies(handle),
+        };
+        self.cache.lock().unwrap().entries.insert(key, cached);
+        Ok(processed)
+    }
+
+    /// Drops the cached shaders that are, or may import, the shader stored under `handle`.
+    fn invalidate(&mut self, handle: ShaderHandle) {
+        let dependents = self.dependents(handle);
+        self.cache
+            .get_mut()
+            .unwrap()
+            .entries
+            .retain(|(cached_handle, _), cached| {
+                *cached_handle != handle
+                    && !cached.dependencies.contains(&handle)
+                    && !dependents.contains(cached_handle)
+            });
+    }
+
+    /// The shaders in the library that the shader stored under `handle` imports directly. This
+    /// includes imports in inactive `#if*` blocks, since they may become active with other defs.
+    pub fn direct_imports(&self, handle: ShaderHandle) -> Vec<ShaderHandle> {
+        let shader = match self.get(handle) {
+            Some(shader) => shader,
+            None => return Vec::new(),
+        };
+        let policy = self.processor.policy();
+        let mut imports = Vec::new();
+        for import in shader.imports() {
+            let candidates = match import {
+                ShaderImport::AssetPath(path) => policy
+                    .asset_path_candidates(shader.asset_path(), path)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(ShaderImport::AssetPath)
+                    .collect(),
+                ShaderImport::Custom(_) => vec![import.clone()],
+            };
+            let resolved = candidates
+                .iter()
+                .find_map(|candidate| self.get_handle(candidate));
+            if let Some(resolved) = resolved {
+                if !imports.contains(&resolved) {
+                    imports.push(resolved);
+                }
+            }
+        }
+        imports
+    }
+
+    /// The shaders that the shader stored under `handle` imports, directly or through other imports.
+    pub fn dependencies(&self, handle: ShaderHandle) -> HashSet<ShaderHandle> {
+        let mut dependencies = HashSet::new();
+        let mut pending = self.direct_imports(handle);
+        while let Some(dependency) = pending.pop() {
+            if dependencies.insert(dependency) {
+                pending.extend(self.direct_imports(dependency));
+            }
+        }
+        dependencies
+    }
+
+    /// The shaders that import the shader stored under `handle`, directly or through other imports.
+    pub fn dependents(&self, handle: ShaderHandle) -> HashSet<ShaderHandle> {
+        self.shaders
+            .keys()
+            .copied()
+            .filter(|&other| self.dependencies(other).contains(&handle))
+            .collect()
+    }
+
+    fn register_import_path(
+        &mut self,
+        handle: ShaderHandle,
+        shader: &Shader,
+    ) -> Result<(), ShaderLibraryError> {
+        for import in import_keys(shader) {
+            match self.import_handles.get(&import) {
+                Some(&existing) if existing != handle => {
+                    return Err(ShaderLibraryError::DuplicateImportPath { import, existing })
+                }
+                _ => {}
+            }
+        }
+        for import in import_keys(shader) {
+            self.import_handles.insert(import, handle);
+        }
+        Ok(())
+    }
+}
+
+impl ShaderSourceProvider for ShaderLibrary {
+    fn resolve(
+        &self,
+        import: &ShaderImport,
+    ) -> Result<Option<Cow<'_, Shader>>, ProcessShaderError> {
+        Ok(self.get_by_import(import).map(Cow::Borrowed))
+    }
+}
+
+/// The imports `shader` can be imported by.
+fn import_keys(shader: &Shader) -> impl Iterator<Item = ShaderImport> + '_ {
+    let asset_path = shader
+        .asset_path()
+        .map(|path| ShaderImport::AssetPath(path.to_string()));
+    shader.import_path().cloned().into_iter().chain(asset_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats(hits: u64, misses: u64, entries: usize) -> ShaderCacheStats {
+        ShaderCacheStats {
+            hits,
+            misses,
+            entries,
+        }
+    }
+
+    #[test]
+    fn processed_shaders_are_cached_per_def_set() {
+        let mut library = ShaderLibrary::default();
+        let handle = library
+            .add(Shader::from_wgsl("#ifdef A\nfn a() {}\n#endif"))
+            .unwrap();
+        let a_and_b = [ShaderDefVal::from("A"), ShaderDefVal::from("B")];
+        let b_and_a = [ShaderDefVal::from("B"), ShaderDefVal::from("A")];
+
+        library.process(handle, &a_and_b).unwrap();
+        assert_eq!(library.cache_stats(), stats(0, 1, 1));
+        // Equivalent def lists share an entry.
+        library.process(handle, &b_and_a).unwrap();
+        assert_eq!(library.cache_stats(), stats(1, 1, 1));
+        library.process(handle, &[]).unwrap();
+        assert_eq!(library.cache_stats(), stats(1, 2, 2));
+
+        library.clear_cache();
+        library.process(handle, &[]).unwrap();
+        assert_eq!(library.cache_stats(), stats(1, 3, 1));
+    }
+
+    #[test]
+    fn replacing_a_shader_only_invalidates_its_dependents() {
+        let mut library = ShaderLibrary::default();
+        let common = library
+            .add(Shader::from_wgsl(
+                "#define_import_path common\nfn common() {}",
+            ))
+            .unwrap();
+        let lighting = library
+            .add(Shader::from_wgsl(
+                "#define_import_path lighting\n#import common\nfn lighting() {}",
+            ))
+            .unwrap();
+        let main = library
+            .add(Shader::from_wgsl("#import lighting\nfn main() {}"))
+            .unwrap();
+        let unrelated = library.add(Shader::from_wgsl("fn unrelated() {}")).unwrap();
+        let handles = [common, lighting, main, unrelated];
+        for handle in handles {
+            library.process(handle, &[]).unwrap();
+        }
+        assert_eq!(library.cache_stats(), stats(0, 4, 4));
+        assert_eq!(library.dependents(common), HashSet::from([lighting, main]));
+
+        library
+            .replace(
+                common,
+                Shader::from_wgsl("#define_import_path common\nfn common2() {}"),
+            )
+            .unwrap();
+        assert_eq!(library.cache_stats(), stats(0, 4, 1));
+        library.process(unrelated, &[]).unwrap();
+        assert_eq!(library.cache_stats(), stats(1, 4, 1));
+        let processed = library.process(main, &[]).unwrap();
+        assert!(processed
+            .get_wgsl_source()
+            .unwrap()
+            .contains("fn common2()"));
+        assert_eq!(library.cache_stats(), stats(1, 5, 2));
+
+        library
+            .replace(main, Shader::from_wgsl("fn main() {}"))
+            .unwrap();
+        assert_eq!(library.cache_stats(), stats(1, 5, 1));
+        library.process(unrelated, &[]).unwrap();
+        assert_eq!(library.cache_stats(), stats(2, 5, 1));
+    }
+
+    #[test]
+    fn conflicting_import_paths_are_rejected() {
+        let mut library = ShaderLibrary::default();
+        let conflicting = || {
+            Shader::from_wgsl("#define_import_path lib::noise\nfn noise() {}")
+                .with_import_path("lib::simplex")
+        };
+        let err = ProcessShaderError::ConflictingImportPath {
+            declared: "lib::noise".into(),
+            set: ShaderImport::Custom("lib::simplex".into()),
+        };
+        assert!(matches!(
+            library.add(conflicting()),
+            Err(ShaderLibraryError::Process(e)) if e == err
+        ));
+        assert!(library.is_empty());
+        assert_eq!(
+            library.get_handle(&ShaderImport::Custom("lib::simplex".into())),
+            None
+        );
+
+        let handle = library
+            .add(Shader::from_wgsl("fn noise() {}").with_import_path("lib::noise"))
+            .unwrap();
+        assert!(matches!(
+            library.replace(handle, conflicting()),
+            Err(ShaderLibraryError::Process(e)) if e == err
+        ));
+        assert_eq!(
+            library.get_handle(&ShaderImport::Custom("lib::noise".into())),
+            Some(handle)
+        );
+    }
+
+    #[test]
+    fn imports_report_the_parse_errors_of_imported_shaders() {
+        let mut library = ShaderLibrary::default();
+        let common = Shader::from_wgsl(
+            "#define_import_path common
+#endif
+fn common() {}",
+        );
+        assert_eq!(common.declared_import_path(), Some("common"));
+        let common = library.add(common).unwrap();
+        let main = library
+            .add(Shader::from_wgsl(
+                "#import common
+fn main() {}",
+            ))
+            .unwrap();
+        assert_eq!(
+            library.get_handle(&ShaderImport::Custom("common".into())),
+            Some(common)
+        );
+        assert!(matches!(
+            library.process(main, &[]),
+            Err(ShaderLibraryError::Process(
+                ProcessShaderError::TooManyEndIfs
+            ))
+        ));
+    }
+}
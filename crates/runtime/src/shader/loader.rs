@@ -0,0 +1,261 @@
+use std::{
+    borrow::Cow,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use thiserror::Error;
+
+use super::{
+    policy, ImportPolicy, PrecompiledShader, PrecompiledShaderError, ProcessShaderError, Shader,
+    ShaderHandle, ShaderImport, ShaderLibrary, ShaderLibraryError, ShaderSourceProvider,
+};
+
+#[derive(Debug, Error)]
+pub enum ShaderLoadError {
+    #[error("Failed to read shader file {path:?}: {source}")]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[error(
+        "Unsupported shader file {0:?}. Expected a .wgsl, .vert, .frag, .comp, .spv or .spvm file."
+    )]
+    UnsupportedExtension(PathBuf),
+    #[error("Failed to read precompiled shader file {path:?}: {source}")]
+    Precompiled {
+        path: PathBuf,
+        #[source]
+        source: PrecompiledShaderError,
+    },
+    #[error("The shader asset '{0}' was not found in any root directory.")]
+    NotFound(String),
+    #[error(transparent)]
+    Library(#[from] ShaderLibraryError),
+    #[error(transparent)]
+    Process(#[from] ProcessShaderError),
+}
+
+/// Loads shader files from one or more root directories into a [`ShaderLibrary`].
+///
+/// Each file is registered under its [asset path](Shader::asset_path): its `/`-separated path
+/// relative to the root it was found in. Roots are searched in order, so a file in an earlier root
+/// shadows one with the same asset path in a later root.
+#[derive(Debug, Clone, Default)]
+pub struct ShaderLoader {
+    roots: Vec<PathBuf>,
+    policy: ImportPolicy,
+}
+
+impl ShaderLoader {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            roots: vec![root.into()],
+            policy: ImportPolicy::default(),
+        }
+    }
+
+    pub fn add_root(&mut self, root: impl Into<PathBuf>) {
+        self.roots.push(root.into());
+    }
+
+    pub fn with_root(mut self, root: impl Into<PathBuf>) -> Self {
+        self.add_root(root);
+        self
+    }
+
+    #[inline]
+    pub fn roots(&self) -> &[PathBuf] {
+        &self.roots
+    }
+
+    /// Sets the policy that loaded asset paths and files are checked against. Use the same policy
+    /// for the [`ShaderProcessor`](super::ShaderProcessor) that processes the loaded shaders.
+    pub fn set_policy(&mut self, policy: ImportPolicy) {
+        self.policy = policy;
+    }
+
+    pub fn with_policy(mut self, policy: ImportPolicy) -> Self {
+        self.set_policy(policy);
+        self
+    }
+
+    #[inline]
+    pub fn policy(&self) -> &ImportPolicy {
+        &self.policy
+    }
+
+    /// Loads the shader at `asset_path`, and recursively the files it imports with
+    /// `#import "path"`, into `library`. Shaders that are already in the library aren't loaded
+    /// again.
+    ///
+    /// Imports are resolved relative to the importing file first, then relative to the roots.
+    /// Imports that can't be found are skipped here and reported when the shader is processed.
+    pub fn load(
+        &self,
+        library: &mut ShaderLibrary,
+        asset_path: &str,
+    ) -> Result<ShaderHandle, ShaderLoadError> {
+        let asset_path = self
+            .policy
+            .asset_path_candidates(None, asset_path)?
+            .remove(0);
+        if let Some(handle) = library.get_handle(&ShaderImport::AssetPath(asset_path.clone())) {
+            return Ok(handle);
+        }
+        let file = self
+            .find(&asset_path)?
+            .ok_or_else(|| ShaderLoadError::NotFound(asset_path.clone()))?;
+        let shader = self.read_checked(&file)?.with_asset_path(&asset_path);
+        let handle = library.add(shader)?;
+        self.load_imports(library, handle)?;
+        Ok(handle)
+    }
+
+    /// Loads the files that the shader stored under `handle` imports with `#import "path"` and
+    /// that aren't in `library` yet.
+    pub fn load_imports(
+        &self,
+        library: &mut ShaderLibrary,
+        handle: ShaderHandle,
+    ) -> Result<(), ShaderLoadError> {
+        let shader = match library.get(handle) {
+            Some(shader) => shader,
+            None => return Err(ShaderLibraryError::UnknownShader(handle).into()),
+        };
+        let asset_path = shader.asset_path().map(str::to_string);
+        let imports = shader
+            .imports()
+            .filter_map(|import| match import {
+                ShaderImport::AssetPath(path) => Some(path.clone()),
+                ShaderImport::Custom(_) => None,
+            })
+            .collect::<Vec<_>>();
+
+        for import in imports {
+            let candidates = self
+                .policy
+                .asset_path_candidates(asset_path.as_deref(), &import)?;
+            for candidate in candidates {
+                if library
+                    .get_handle(&ShaderImport::AssetPath(candidate.clone()))
+                    .is_some()
+                    || self.find(&candidate)?.is_some()
+                {
+                    self.load(library, &candidate)?;
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The file `asset_path` refers to in the first root that contains it. Unless the policy
+    /// allows it, a file that is a symlink to somewhere outside of its root is an error.
+    pub fn find(&self, asset_path: &str) -> Result<Option<PathBuf>, ShaderLoadError> {
+        for root in &self.roots {
+            let path = root.join(asset_path);
+            if !path.is_file() {
+                continue;
+            }
+            // Paths that leave the root with `..` are up to `allow_root_escapes` instead.
+            if !self.policy.allows_symlink_escapes() && !policy::escapes_root(asset_path) {
+                let canonicalize = |path: &Path| {
+                    path.canonicalize().map_err(|source| ShaderLoadError::Io {
+                        path: path.to_path_buf(),
+                        source,
+                    })
+                };
+                let target = canonicalize(&path)?;
+                if !target.starts_with(canonicalize(root)?) {
+                    return Err(ProcessShaderError::SymlinkEscapesRoot {
+                        asset_path: asset_path.to_string(),
+                        target,
+                    }
+                    .into());
+                }
+            }
+            return Ok(Some(path));
+        }
+        Ok(None)
+    }
+
+    /// Reads the shader file at `path`, checking its size against the policy first.
+    pub(super) fn read_checked(&self, path: &Path) -> Result<Shader, ShaderLoadError> {
+        if self.policy.max_expanded_size().is_some() {
+            let size = fs::metadata(path)
+                .map_err(|source| ShaderLoadError::Io {
+                    path: path.to_path_buf(),
+                    source,
+                })?
+                .len();
+            self.policy
+                .check_expanded_size(usize::try_from(size).unwrap_or(usize::MAX))?;
+        }
+        Self::read_shader(path)
+    }
+
+    /// Reads the shader file at `path`, picking its [`Source`](super::Source) from the file
+    /// extension: `.wgsl` for WGSL, `.vert`, `.frag` and `.comp` for GLSL, `.spv` for SPIR-V and
+    /// `.spvm` for a [`PrecompiledShader`](super::PrecompiledShader).
+    pub fn read_shader(path: &Path) -> Result<Shader, ShaderLoadError> {
+        let io_error = |source| ShaderLoadError::Io {
+            path: path.to_path_buf(),
+            source,
+        };
+        let extension = path.extension().and_then(|extension| extension.to_str());
+        let stage = match extension {
+            Some("wgsl") => None,
+            Some("vert") => Some(naga::ShaderStage::Vertex),
+            Some("frag") => Some(naga::ShaderStage::Fragment),
+            Some("comp") => Some(naga::ShaderStage::Compute),
+            Some("spv") => {
+                return Ok(Shader::from_spirv(fs::read(path).map_err(io_error)?));
+            }
+            Some("spvm") => {
+                let bytes = fs::read(path).map_err(io_error)?;
+                let precompiled = PrecompiledShader::from_bytes(&bytes).map_err(|source| {
+                    ShaderLoadError::Precompiled {
+                        path: path.to_path_buf(),
+                        source,
+                    }
+                })?;
+                return Ok(Shader::from_precompiled_spirv(precompiled));
+            }
+            _ => return Err(ShaderLoadError::UnsupportedExtension(path.to_path_buf())),
+        };
+        let source = fs::read_to_string(path).map_err(io_error)?;
+        Ok(match stage {
+            Some(stage) => Shader::from_glsl(source, stage),
+            None => Shader::from_wgsl(source),
+        })
+    }
+}
+
+/// Loads `#import "path"` directives from the roots on demand, without registering the loaded
+/// shaders anywhere.
+impl ShaderSourceProvider for ShaderLoader {
+    fn resolve(
+        &self,
+        import: &ShaderImport,
+    ) -> Result<Option<Cow<'_, Shader>>, ProcessShaderError> {
+        let asset_path = match import {
+            ShaderImport::AssetPath(asset_path) => asset_path,
+            ShaderImport::Custom(_) => return Ok(None),
+        };
+        let load_failed = |err: ShaderLoadError| match err {
+            ShaderLoadError::Process(err) => err,
+            err => ProcessShaderError::ImportLoadFailed {
+                import: import.clone(),
+                message: err.to_string(),
+            },
+        };
+        let file = match self.find(asset_path).map_err(load_failed)? {
+            Some(file) => file,
+            None => return Ok(None),
+        };
+        let shader = self.read_checked(&file).map_err(load_failed)?;
+        Ok(Some(Cow::Owned(shader.with_asset_path(asset_path))))
+    }
+}
@@ -0,0 +1,142 @@
+use std::{
+    hash::{Hash, Hasher},
+    sync::Arc,
+};
+
+use naga::{valid::ModuleInfo, Module};
+use serde::{Deserialize, Serialize};
+
+use super::{ShaderReflection, ValidationConfig};
+
+/// Parsed naga IR, optionally with the [`ModuleInfo`] from validating it and the
+/// [`ValidationConfig`] it was validated with.
+///
+/// It implements `Serialize` and `Deserialize`, so parsed and validated shaders can be cached on
+/// disk in any serde format. A [`Shader::from_naga_module`](super::Shader::from_naga_module) skips
+/// WGSL and GLSL parsing, and if the info is present validation too, unless it's reflected with a
+/// stricter config than the one the info came from.
+///
+/// ```ignore
+/// let naga_module = NagaModule::from(processed.reflect()?);
+/// fs::write("pbr.naga", bincode::serialize(&naga_module)?)?;
+/// // On the next start:
+/// let naga_module: NagaModule = bincode::deserialize(&fs::read("pbr.naga")?)?;
+/// let shader = Shader::from_naga_module(naga_module);
+/// ```
+///
+/// Naga IR can't be compared structurally, so two `NagaModule`s are only equal if one is a clone
+/// of the other.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NagaModule {
+    module: Arc<Module>,
+    info: Option<Arc<ModuleInfo>>,
+    validation: ValidationConfig,
+}
+
+impl NagaModule {
+    /// A module that wasn't validated yet.
+    pub fn new(module: Module) -> Self {
+        Self {
+            module: Arc::new(module),
+            info: None,
+            validation: ValidationConfig::default(),
+        }
+    }
+
+    /// A module with the `info` from validating it with `validation`. The info is trusted as is,
+    /// so it must come from validating this `module` with that config.
+    pub fn validated(module: Module, info: ModuleInfo, validation: ValidationConfig) -> Self {
+        Self {
+            module: Arc::new(module),
+            info: Some(Arc::new(info)),
+            validation,
+        }
+    }
+
+    #[inline]
+    pub fn module(&self) -> &Arc<Module> {
+        &self.module
+    }
+
+    #[inline]
+    pub fn info(&self) -> Option<&Arc<ModuleInfo>> {
+        self.info.as_ref()
+    }
+
+    /// The config the [`info`](Self::info) was validated with.
+    #[inline]
+    pub fn validation(&self) -> &ValidationConfig {
+        &self.validation
+    }
+}
+
+impl From<ShaderReflection> for NagaModule {
+    fn from(reflection: ShaderReflection) -> Self {
+        Self {
+            module: reflection.module,
+            info: Some(reflection.module_info),
+            validation: reflection.validation,
+        }
+    }
+}
+
+impl PartialEq for NagaModule {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.module, &other.module)
+    }
+}
+
+impl Eq for NagaModule {}
+
+impl Hash for NagaModule {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        Arc::as_ptr(&self.module).hash(state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use naga::valid::Capabilities;
+
+    use super::super::ProcessedShader;
+    use super::*;
+
+    const PUSH_CONSTANTS: &str = "struct Constants { scale: f32; };
+var<push_constant> constants: Constants;
+[[stage(fragment)]]
+fn main() -> [[location(0)]] vec4<f32> { return vec4<f32>(constants.scale); }";
+
+    #[test]
+    fn serialized_modules_keep_their_validation() {
+        let push_constants =
+            ValidationConfig::default().with_capabilities(Capabilities::PUSH_CONSTANT);
+        let reflection = ProcessedShader::Wgsl(PUSH_CONSTANTS.into())
+            .reflect_with_validation(&push_constants)
+            .unwrap();
+        let wgsl = reflection.get_wgsl().unwrap();
+
+        let json = serde_json::to_string(&NagaModule::from(reflection)).unwrap();
+        let naga_module: NagaModule = serde_json::from_str(&json).unwrap();
+        assert!(naga_module.info().is_some());
+        assert_eq!(naga_module.validation(), &push_constants);
+
+        let shader = ProcessedShader::NagaModule(naga_module);
+        let reflection = shader.reflect_with_validation(&push_constants).unwrap();
+        assert_eq!(reflection.get_wgsl().unwrap(), wgsl);
+        // Without the capability the module is validated again, and rejected.
+        assert!(shader.reflect().is_err());
+    }
+
+    #[test]
+    fn unvalidated_modules_are_validated_when_reflected() {
+        let module = naga::front::wgsl::parse_str(PUSH_CONSTANTS).unwrap();
+        let shader = ProcessedShader::NagaModule(NagaModule::new(module));
+        assert!(shader.reflect().is_err());
+
+        let push_constants =
+            ValidationConfig::default().with_capabilities(Capabilities::PUSH_CONSTANT);
+        let reflection = shader.reflect_with_validation(&push_constants).unwrap();
+        assert_eq!(reflection.validation, push_constants);
+        assert_eq!(NagaModule::from(reflection).validation(), &push_constants);
+    }
+}
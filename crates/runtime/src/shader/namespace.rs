@@ -0,0 +1,376 @@
+use super::{ImportShaderDef, ShaderDefVal};
+use std::collections::HashMap;
+
+/// Keywords that introduce a named module-scope declaration in WGSL or GLSL.
+const DECLARATION_KEYWORDS: &[&str] =
+    &["fn", "struct", "type", "alias", "let", "const", "override"];
+
+/// The name `item`, declared in the namespaced module `module` imported with the (normalized) def
+/// changes `shader_defs`, is emitted with.
+///
+/// Each `_` in the module path is written as `_u` and each `::` as `_s`. The def changes follow
+/// after `_w`, each as `_d` and the escaped def name followed by `_t` or `_f` for a bool, `_i`
+/// (`_in` if negative) and the digits for an int, `_p` and the digits for a uint, or `_r` for a
+/// removed def. So different module instances never share a prefix, and the prefix always ends at
+/// the first `__`.
+pub(super) fn mangle(module: &str, shader_defs: &[ImportShaderDef], item: &str) -> String {
+    let mut prefix = escape(module);
+    if !shader_defs.is_empty() {
+        prefix.push_str("_w");
+    }
+    for def in shader_defs {
+        prefix.push_str("_d");
+        prefix.push_str(&escape(def.name()));
+        let value = match def {
+            ImportShaderDef::Set(ShaderDefVal::Bool(_, true)) => "_t".to_string(),
+            ImportShaderDef::Set(ShaderDefVal::Bool(_, false)) => "_f".to_string(),
+            ImportShaderDef::Set(ShaderDefVal::Int(_, value)) if *value < 0 => {
+                format!("_in{}", value.unsigned_abs())
+            }
+            ImportShaderDef::Set(ShaderDefVal::Int(_, value)) => format!("_i{}", value),
+            ImportShaderDef::Set(ShaderDefVal::UInt(_, value)) => format!("_p{}", value),
+            ImportShaderDef::Remove(_) => "_r".to_string(),
+        };
+        prefix.push_str(&value);
+    }
+    format!("{}__{}", prefix, item)
+}
+
+fn escape(path: &str) -> String {
+    path.replace('_', "_u").replace("::", "_s")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TokenKind {
+    /// An identifier, or a qualified path like `lighting::pbr`.
+    Path,
+    /// A numeric literal. Lexed separately so suffixes like the `u` in `1u` aren't identifiers.
+    Number,
+    Punct(char),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Token {
+    kind: TokenKind,
+    start: usize,
+    end: usize,
+}
+
+/// Splits `source` into tokens, skipping whitespace and comments.
+fn tokenize(source: &str) -> Vec<Token> {
+    let bytes = source.as_bytes();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let c = bytes[i];
+        if c.is_ascii_whitespace() {
+            i += 1;
+        } else if source[i..].starts_with("//") {
+            i = source[i..].find('\n').map_or(bytes.len(), |end| i + end);
+        } else if source[i..].starts_with("/*") {
+            i = source[i + 2..]
+                .find("*/")
+                .map_or(bytes.len(), |end| i + 2 + end + 2);
+        } else if c.is_ascii_digit() {
+            let start = i;
+            while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'.') {
+                i += 1;
+            }
+            tokens.push(Token {
+                kind: TokenKind::Number,
+                start,
+                end: i,
+            });
+        } else if c.is_ascii_alphabetic() || c == b'_' {
+            let start = i;
+            loop {
+                while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
+                    i += 1;
+                }
+                let continues_path = source[i..].starts_with("::")
+                    && bytes
+                        .get(i + 2)
+                        .is_some_and(|&c| c.is_ascii_alphabetic() || c == b'_');
+                if !continues_path {
+                    break;
+                }
+                i += 2;
+            }
+            tokens.push(Token {
+                kind: TokenKind::Path,
+                start,
+                end: i,
+            });
+        } else {
+            let c = source[i..].chars().next().unwrap();
+            tokens.push(Token {
+                kind: TokenKind::Punct(c),
+                start: i,
+                end: i + c.len_utf8(),
+            });
+            i += c.len_utf8();
+        }
+    }
+    tokens
+}
+
+/// Returns the names of the top-level functions, structs, type aliases, module-scope variables and
+/// constants declared in `source`, in declaration order.
+///
+/// WGSL declarations are recognized by their keyword. For GLSL only functions (`type name(`) and
+/// structs are recognized.
+pub(super) fn declared_items(source: &str) -> Vec<String> {
+    let tokens = tokenize(source);
+    let text = |token: &Token| &source[token.start..token.end];
+    let mut items = Vec::<String>::new();
+    let mut depth = 0usize;
+    let mut i = 0;
+    while i < tokens.len() {
+        let token = &tokens[i];
+        match token.kind {
+            TokenKind::Punct('{') | TokenKind::Punct('(') => depth += 1,
+            TokenKind::Punct('}') | TokenKind::Punct(')') => depth = depth.saturating_sub(1),
+            TokenKind::Path if depth == 0 => {
+                let mut name_index = None;
+                if text(token) == "const"
+                    && matches!(tokens.get(i + 2), Some(t) if t.kind == TokenKind::Path)
+                {
+                    // A GLSL constant, `const float PI = ...`.
+                    name_index = Some(i + 2);
+                } else if DECLARATION_KEYWORDS.contains(&text(token)) {
+                    name_index = Some(i + 1);
+                } else if text(token) == "var" {
+                    // Skip the address space and access mode, as in `var<uniform>`.
+                    let mut j = i + 1;
+                    if matches!(tokens.get(j), Some(t) if t.kind == TokenKind::Punct('<')) {
+                        while j < tokens.len() && tokens[j].kind != TokenKind::Punct('>') {
+                            j += 1;
+                        }
+                        j += 1;
+                    }
+                    name_index = Some(j);
+                } else if matches!(tokens.get(i + 1), Some(t) if t.kind == TokenKind::Path)
+                    && matches!(tokens.get(i + 2), Some(t) if t.kind == TokenKind::Punct('('))
+                {
+                    // A GLSL function, `vec3 pbr(...)`.
+                    name_index = Some(i + 1);
+                }
+                if let Some(name) = name_index.and_then(|j| tokens.get(j)) {
+                    if name.kind == TokenKind::Path {
+                        let name = text(name);
+                        if !items.iter().any(|item| item == name) {
+                            items.push(name.to_string());
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    items
+}
+
+/// Replaces every identifier or qualified path in `source` that has an entry in `replacements`.
+///
+/// Member accesses (`light.color`), struct member declarations and attributes (`@builtin(position)`
+/// or `[[builtin(position)]]`) are left untouched, so only references to module-scope items are
+/// rewritten.
+///
+/// Scopes aren't tracked: a local variable or function parameter with the name of a module-scope
+/// item is rewritten along with every use of it, as if it referred to that item.
+pub(super) fn rewrite_identifiers(source: &str, replacements: &HashMap<String, String>) -> String {
+    if replacements.is_empty() {
+        return source.to_string();
+    }
+
+    let tokens = tokenize(source);
+    let text = |token: &Token| &source[token.start..token.end];
+    let kind_at = |i: usize| tokens.get(i).map(|token| token.kind);
+    let mut output = String::with_capacity(source.len());
+    let mut last_end = 0;
+    // For each open brace, whether it opened a struct body.
+    let mut braces = Vec::<bool>::new();
+    let mut struct_pending = false;
+    // Parenthesis depth of the attribute arguments currently being skipped, if any.
+    let mut attribute_depth: Option<usize> = None;
+    let mut paren_depth = 0usize;
+    // Whether we're inside a `[[location(0)]]` style attribute list.
+    let mut in_attribute_list = false;
+    for (i, token) in tokens.iter().enumerate() {
+        let previous = i.checked_sub(1).and_then(kind_at);
+        match token.kind {
+            TokenKind::Punct('{') => {
+                braces.push(struct_pending);
+                struct_pending = false;
+            }
+            TokenKind::Punct('}') => {
+                braces.pop();
+            }
+            TokenKind::Punct('(') => {
+                paren_depth += 1;
+                if attribute_depth.is_none()
+                    && previous == Some(TokenKind::Path)
+                    && i >= 2
+                    && kind_at(i - 2) == Some(TokenKind::Punct('@'))
+                {
+                    attribute_depth = Some(paren_depth);
+                }
+            }
+            TokenKind::Punct('[') if previous == Some(TokenKind::Punct('[')) => {
+                in_attribute_list = true;
+            }
+            TokenKind::Punct(']') if previous == Some(TokenKind::Punct(']')) => {
+                in_attribute_list = false;
+            }
+            TokenKind::Punct(')') => {
+                if attribute_depth == Some(paren_depth) {
+                    attribute_depth = None;
+                }
+                paren_depth = paren_depth.saturating_sub(1);
+            }
+            TokenKind::Path => {
+                if text(token) == "struct" {
+                    struct_pending = true;
+                }
+                let in_struct_body = braces.last().copied().unwrap_or(false);
+                let next = kind_at(i + 1);
+                let skip = attribute_depth.is_some()
+                    || in_attribute_list
+                    || matches!(previous, Some(TokenKind::Punct('.' | '@')))
+                    || (in_struct_body
+                        && (next == Some(TokenKind::Punct(':'))
+                            || previous == Some(TokenKind::Path)));
+                if skip {
+                    continue;
+                }
+                if let Some(replacement) = replacements.get(text(token)) {
+                    output.push_str(&source[last_end..token.start]);
+                    output.push_str(replacement);
+                    last_end = token.end;
+                }
+            }
+            _ => {}
+        }
+    }
+    output.push_str(&source[last_end..]);
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn module_names(source: &str) -> HashMap<String, String> {
+        declared_items(source)
+            .into_iter()
+            .map(|item| {
+                let mangled = mangle("lighting::pbr", &[], &item);
+                (item, mangled)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn mangles_module_paths() {
+        assert_eq!(
+            mangle("lighting::pbr", &[], "light"),
+            "lighting_spbr__light"
+        );
+        assert_eq!(mangle("common", &[], "PI"), "common__PI");
+        assert_eq!(mangle("lib::my_mod", &[], "_x"), "lib_smy_umod___x");
+    }
+
+    #[test]
+    fn mangled_module_paths_dont_collide() {
+        assert_ne!(mangle("a_b::c", &[], "item"), mangle("a::b_c", &[], "item"));
+        assert_ne!(mangle("a_::b", &[], "item"), mangle("a::_b", &[], "item"));
+        assert_ne!(
+            mangle("a::b", &[], "c__item"),
+            mangle("a::b__c", &[], "item")
+        );
+        assert_ne!(mangle("a", &[], "s_b__item"), mangle("a::b", &[], "item"));
+    }
+
+    #[test]
+    fn mangled_instances_spell_out_their_defs() {
+        let defs = |defs: &[&str]| -> Vec<ImportShaderDef> {
+            defs.iter().map(|def| def.parse().unwrap()).collect()
+        };
+        assert_eq!(
+            mangle("lib::shadows", &defs(&["SOFT", "TAPS=-8", "!PCF"]), "shade"),
+            "lib_sshadows_w_dSOFT_t_dTAPS_in8_dPCF_r__shade"
+        );
+        assert_ne!(
+            mangle("a", &defs(&["B_U=1"]), "item"),
+            mangle("a", &defs(&["B", "U=1"]), "item")
+        );
+        assert_ne!(
+            mangle("a", &defs(&["B=1"]), "item"),
+            mangle("a", &defs(&["B=-1"]), "item")
+        );
+        assert_ne!(
+            mangle("a", &defs(&["B"]), "item"),
+            mangle("a::_wB_t", &[], "item")
+        );
+    }
+
+    #[test]
+    fn declared_items_skip_locals_and_members() {
+        let source = "
+            struct Light { color: vec3<f32>; intensity: f32; };
+            [[group(0), binding(0)]] var<uniform> light: Light;
+            let PI: f32 = 3.14;
+            type Color = vec3<f32>;
+            fn shade(n: vec3<f32>) -> Color {
+                let intensity = light.intensity;
+                return light.color * intensity;
+            }
+        ";
+        assert_eq!(
+            declared_items(source),
+            ["Light", "light", "PI", "Color", "shade"]
+        );
+        assert_eq!(
+            declared_items("const float PI = 3.14;\nstruct Light { vec3 color; };\nvec3 shade(Light l) { return l.color; }"),
+            ["PI", "Light", "shade"]
+        );
+    }
+
+    #[test]
+    fn struct_members_and_attributes_are_not_mangled() {
+        let source = "
+            struct Light { color: vec3<f32>; position: vec4<f32>; };
+            struct VertexOutput { [[builtin(position)]] position: vec4<f32>; [[location(0)]] color: vec3<f32>; };
+            fn color(light: Light) -> vec3<f32> { return light.color; }
+            fn position() -> vec4<f32> { return vec4<f32>(0.0); }
+            @vertex fn vs() -> @builtin(position) vec4<f32> { return position(); }
+        ";
+        let rewritten = rewrite_identifiers(source, &module_names(source));
+        assert!(rewritten
+            .contains("struct lighting_spbr__Light { color: vec3<f32>; position: vec4<f32>; }"));
+        assert!(rewritten.contains(
+            "[[builtin(position)]] position: vec4<f32>; [[location(0)]] color: vec3<f32>;"
+        ));
+        assert!(rewritten.contains(
+            "fn lighting_spbr__color(light: lighting_spbr__Light) -> vec3<f32> { return light.color; }"
+        ));
+        assert!(rewritten.contains(
+            "@vertex fn lighting_spbr__vs() -> @builtin(position) vec4<f32> { return lighting_spbr__position(); }"
+        ));
+    }
+
+    #[test]
+    fn qualified_and_aliased_references() {
+        let mut names = HashMap::new();
+        for path in ["lighting::pbr::shade", "pbr::shade", "lit::shade"] {
+            names.insert(path.to_string(), mangle("lighting::pbr", &[], "shade"));
+        }
+        names.insert("shade".to_string(), mangle("lighting::pbr", &[], "shade"));
+        let source = "let a = lighting::pbr::shade(n); let b = pbr::shade(n); let c = lit::shade(n); let d = shade(n); let e = other::shade(n); let f = v.shade;";
+        assert_eq!(
+            rewrite_identifiers(source, &names),
+            "let a = lighting_spbr__shade(n); let b = lighting_spbr__shade(n); let c = lighting_spbr__shade(n); let d = lighting_spbr__shade(n); let e = other::shade(n); let f = v.shade;"
+        );
+    }
+}
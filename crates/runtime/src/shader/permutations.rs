@@ -0,0 +1,520 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    sync::Arc,
+};
+
+use thiserror::Error;
+
+use super::{
+    tree::DefReferences, visit_import_graph, ImportPolicy, ProcessShaderError, ProcessedShader,
+    Shader, ShaderDefVal, ShaderProcessor, ShaderReflectError, ShaderSourceProvider, Source,
+    ValidationConfig,
+};
+
+#[derive(Debug, Error)]
+pub enum PermutationError {
+    #[error("Shader has {count} permutations, more than the maximum of {max}. Add constraints to reduce them.")]
+    TooManyPermutations { count: usize, max: usize },
+    #[error("The defs {0:?} are only substituted with '#{{NAME}}', so there are no values to try. Add a 'Fixed' or 'Values' constraint for them.")]
+    UnconstrainedSubstitution(Vec<String>),
+    #[error(transparent)]
+    Process(#[from] ProcessShaderError),
+}
+
+/// Restricts the def combinations a [`PermutationExplorer`] tries.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PermutationConstraint {
+    /// The def is always set to this value. It's passed to every permutation even if the shader
+    /// doesn't reference it.
+    Fixed(ShaderDefVal),
+    /// The def is never set.
+    Unset(String),
+    /// The def is always set to one of these values, instead of the values found in the shader.
+    /// All values must have the same name.
+    Values(Vec<ShaderDefVal>),
+    /// At most one of these defs is set.
+    Exclusive(Vec<String>),
+    /// If the first def is set, so is the second.
+    Requires(String, String),
+}
+
+/// A def consulted by a shader or one of its imports.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReferencedDef {
+    pub name: String,
+    /// The integer values the def is tried with: each literal it's compared against in an `#if`
+    /// expression and, for `<`, `<=`, `>` and `>=`, the closest value on the other side of the
+    /// comparison, so that both outcomes are covered.
+    pub values: Vec<i64>,
+    /// Whether the def is only substituted with `#{NAME}` and never consulted by an `#if*`
+    /// directive. Such a def isn't enumerated, it needs a `Fixed`, `Values` or `Unset` constraint.
+    pub substitution_only: bool,
+}
+
+/// Why a permutation is broken.
+#[derive(Debug, Clone, Error)]
+pub enum PermutationFailure {
+    #[error(transparent)]
+    Process(ProcessShaderError),
+    /// Permutations that process to the same output share the error.
+    #[error(transparent)]
+    Reflect(Arc<ShaderReflectError>),
+}
+
+#[derive(Debug, Clone)]
+pub struct PermutationResult {
+    /// The defs the shader was processed with. Defs that aren't listed were unset.
+    pub shader_defs: Vec<ShaderDefVal>,
+    pub failure: Option<PermutationFailure>,
+}
+
+#[derive(Debug, Clone)]
+pub struct PermutationReport {
+    pub referenced_defs: Vec<ReferencedDef>,
+    pub results: Vec<PermutationResult>,
+    /// How many different shaders the permutations processed to. Each one is reflected only once.
+    pub distinct_outputs: usize,
+}
+
+impl PermutationReport {
+    pub fn failures(&self) -> impl Iterator<Item = &PermutationResult> {
+        self.results
+            .iter()
+            .filter(|result| result.failure.is_some())
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.failures().next().is_none()
+    }
+}
+
+/// Finds the defs a shader and its imports consult, and processes and validates the shader with
+/// every combination of them.
+///
+/// Each def referenced by an `#if*` directive is tried unset, set to `true` and set to each integer
+/// it's compared against in an `#if`, plus the integer just past a `<`, `<=`, `>` or `>=` bound.
+/// Defs that are only substituted need a constraint. Imports are followed in all branches, so a def
+/// that's only consulted by an import behind an `#ifdef` is still found. [`PermutationConstraint`]s prune combinations that can't
+/// happen at runtime.
+///
+/// ```ignore
+/// let report = PermutationExplorer::new(library.processor(), &library)
+///     .with_constraint(PermutationConstraint::Exclusive(vec!["PCF".into(), "VSM".into()]))
+///     .explore(library.get(handle).unwrap())?;
+/// for failure in report.failures() {
+///     eprintln!("{:?}: {}", failure.shader_defs, failure.failure.as_ref().unwrap());
+/// }
+/// ```
+pub struct PermutationExplorer<'a> {
+    processor: &'a ShaderProcessor,
+    provider: &'a dyn ShaderSourceProvider,
+    constraints: Vec<PermutationConstraint>,
+    max_permutations: usize,
+    validation: ValidationConfig,
+}
+
+impl<'a> PermutationExplorer<'a> {
+    pub const DEFAULT_MAX_PERMUTATIONS: usize = 4096;
+
+    pub fn new(processor: &'a ShaderProcessor, provider: &'a dyn ShaderSourceProvider) -> Self {
+        Self {
+            processor,
+            provider,
+            constraints: Vec::new(),
+            max_permutations: Self::DEFAULT_MAX_PERMUTATIONS,
+            validation: ValidationConfig::default(),
+        }
+    }
+
+    pub fn add_constraint(&mut self, constraint: PermutationConstraint) {
+        self.constraints.push(constraint);
+    }
+
+    pub fn with_constraint(mut self, constraint: PermutationConstraint) -> Self {
+        self.add_constraint(constraint);
+        self
+    }
+
+    /// Caps how many permutations may be enumerated, counted before the `Exclusive` and `Requires`
+    /// constraints are applied. More fail with [`PermutationError::TooManyPermutations`].
+    pub fn set_max_permutations(&mut self, max_permutations: usize) {
+        self.max_permutations = max_permutations;
+    }
+
+    pub fn with_max_permutations(mut self, max_permutations: usize) -> Self {
+        self.set_max_permutations(max_permutations);
+        self
+    }
+
+    /// Sets how each permutation is validated, for example with the features of the device the
+    /// shader will be used on.
+    pub fn set_validation(&mut self, validation: ValidationConfig) {
+        self.validation = validation;
+    }
+
+    pub fn with_validation(mut self, validation: ValidationConfig) -> Self {
+        self.set_validation(validation);
+        self
+    }
+
+    /// The defs consulted by `shader` and the shaders it imports, sorted by name. Imports that
+    /// can't be resolved are skipped.
+    pub fn referenced_defs(
+        &self,
+        shader: &Shader,
+    ) -> Result<Vec<ReferencedDef>, ProcessShaderError> {
+        let referenced = collect_referenced_defs(shader, self.provider, self.processor.policy())?;
+        Ok(referenced
+            .into_iter()
+            .map(|(name, references)| ReferencedDef {
+                name,
+                values: references
+                    .comparisons
+                    .into_iter()
+                    .flat_map(|(comparison, literal)| comparison.boundary_values(literal))
+                    .collect::<BTreeSet<_>>()
+                    .into_iter()
+                    .collect(),
+                substitution_only: !references.conditional,
+            })
+            .collect())
+    }
+
+    /// Every def combination allowed by the constraints. Defs that aren't set are left out.
+    pub fn permutations(
+        &self,
+        shader: &Shader,
+    ) -> Result<Vec<Vec<ShaderDefVal>>, PermutationError> {
+        let referenced = self.referenced_defs(shader)?;
+        self.enumerate(&referenced)
+    }
+
+    fn enumerate(
+        &self,
+        referenced: &[ReferencedDef],
+    ) -> Result<Vec<Vec<ShaderDefVal>>, PermutationError> {
+        let mut candidates = referenced
+            .iter()
+            .filter(|def| !def.substitution_only)
+            .map(|def| (def.name.clone(), default_candidates(def)))
+            .collect::<BTreeMap<_, _>>();
+        let mut value_constraints = HashMap::<&str, Vec<Option<ShaderDefVal>>>::new();
+        for constraint in &self.constraints {
+            match constraint {
+                PermutationConstraint::Fixed(def) => {
+                    candidates.insert(def.name().to_string(), vec![Some(def.clone())]);
+                }
+                PermutationConstraint::Unset(name) => {
+                    candidates.insert(name.clone(), vec![None]);
+                }
+                PermutationConstraint::Values(values) => {
+                    for value in values {
+                        value_constraints
+                            .entry(value.name())
+                            .or_default()
+                            .push(Some(value.clone()));
+                    }
+                }
+                PermutationConstraint::Exclusive(_) | PermutationConstraint::Requires(..) => {}
+            }
+        }
+        for (name, values) in value_constraints {
+            candidates.insert(name.to_string(), values);
+        }
+        let unconstrained = referenced
+            .iter()
+            .filter(|def| def.substitution_only && !candidates.contains_key(&def.name))
+            .map(|def| def.name.clone())
+            .collect::<Vec<_>>();
+        if !unconstrained.is_empty() {
+            return Err(PermutationError::UnconstrainedSubstitution(unconstrained));
+        }
+
+        let count = candidates.values().try_fold(1usize, |count, values| {
+            count.checked_mul(values.len().max(1))
+        });
+        match count {
+            Some(count) if count <= self.max_permutations => {}
+            count => {
+                return Err(PermutationError::TooManyPermutations {
+                    count: count.unwrap_or(usize::MAX),
+                    max: self.max_permutations,
+                })
+            }
+        }
+
+        let candidates = candidates
+            .into_values()
+            .filter(|values| !values.is_empty())
+            .collect::<Vec<_>>();
+        let mut permutations = Vec::new();
+        let mut indices = vec![0; candidates.len()];
+        loop {
+            let shader_defs = indices
+                .iter()
+                .zip(&candidates)
+                .filter_map(|(&index, values)| values[index].clone())
+                .collect::<Vec<_>>();
+            if self.allows(&shader_defs) {
+                permutations.push(shader_defs);
+            }
+
+            // Advance like an odometer, the last def changing fastest.
+            let mut position = indices.len();
+            loop {
+                if position == 0 {
+                    return Ok(permutations);
+                }
+                position -= 1;
+                indices[position] += 1;
+                if indices[position] < candidates[position].len() {
+                    break;
+                }
+                indices[position] = 0;
+            }
+        }
+    }
+
+    fn allows(&self, shader_defs: &[ShaderDefVal]) -> bool {
+        let is_set = |name: &str| {
+            shader_defs
+                .iter()
+                .any(|def| def.name() == name && def.is_defined())
+        };
+        self.constraints.iter().all(|constraint| match constraint {
+            PermutationConstraint::Exclusive(names) => {
+                names.iter().filter(|name| is_set(name)).count() <= 1
+            }
+            PermutationConstraint::Requires(name, required) => !is_set(name) || is_set(required),
+            _ => true,
+        })
+    }
+
+    /// Processes and reflects `shader` with every permutation. Permutations that process to the
+    /// same output are only reflected once.
+    pub fn explore(&self, shader: &Shader) -> Result<PermutationReport, PermutationError> {
+        let referenced_defs = self.referenced_defs(shader)?;
+        let permutations = self.enumerate(&referenced_defs)?;
+
+        let mut reflected = HashMap::<ProcessedShader, Option<Arc<ShaderReflectError>>>::new();
+        let mut results = Vec::with_capacity(permutations.len());
+        for shader_defs in permutations {
+            let failure =
+                match self
+                    .processor
+                    .process_with_provider(shader, &shader_defs, self.provider)
+                {
+                    Ok(processed) => reflected
+                        .entry(processed)
+                        .or_insert_with_key(|processed| {
+                            processed
+                                .reflect_with_validation(&self.validation)
+                                .err()
+                                .map(Arc::new)
+                        })
+                        .clone()
+                        .map(PermutationFailure::Reflect),
+                    Err(err) => Some(PermutationFailure::Process(err)),
+                };
+            results.push(PermutationResult {
+                shader_defs,
+                failure,
+            });
+        }
+
+        Ok(PermutationReport {
+            referenced_defs,
+            results,
+            distinct_outputs: reflected.len(),
+        })
+    }
+}
+
+/// The defs consulted by `shader` and the shaders it may import, by name. Imports that can't be
+/// resolved are skipped.
+pub(super) fn collect_referenced_defs(
+    shader: &Shader,
+    provider: &dyn ShaderSourceProvider,
+    policy: &ImportPolicy,
+) -> Result<BTreeMap<String, DefReferences>, ProcessShaderError> {
+    let mut referenced = BTreeMap::new();
+    visit_import_graph(shader, provider, policy, &mut |_, shader| {
+        if let Source::Wgsl(source) | Source::Glsl(source, _) = &shader.source {
+            let tree = shader.tree.as_ref().map_err(Clone::clone)?;
+            tree.referenced_defs(source, &mut referenced);
+        }
+        Ok(())
+    })?;
+    Ok(referenced)
+}
+
+/// Unset, `true`, and each of the def's [`values`](ReferencedDef::values) that fits a def value.
+fn default_candidates(def: &ReferencedDef) -> Vec<Option<ShaderDefVal>> {
+    let name = &def.name;
+    let literals = def.values.iter().filter_map(|&value| {
+        if let Ok(value) = i32::try_from(value) {
+            Some(ShaderDefVal::Int(name.clone(), value))
+        } else {
+            u32::try_from(value)
+                .ok()
+                .map(|value| ShaderDefVal::UInt(name.clone(), value))
+        }
+    });
+    [None, Some(ShaderDefVal::Bool(name.clone(), true))]
+        .into_iter()
+        .chain(literals.map(Some))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shader::ShaderImport;
+
+    #[test]
+    fn ordering_guards_are_explored_on_both_sides() {
+        let shader = Shader::from_wgsl(
+            "#if TAPS > 4\nfn broken() -> f32 { return missing; }\n#endif\nfn f() -> f32 { return 1.0; }",
+        );
+        let processor = ShaderProcessor::default();
+        let provider = HashMap::<ShaderImport, Shader>::new();
+        let explorer = PermutationExplorer::new(&processor, &provider);
+
+        let report = explorer.explore(&shader).unwrap();
+        assert_eq!(report.referenced_defs[0].values, vec![4, 5]);
+        let failures = report
+            .failures()
+            .map(|result| result.shader_defs.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(failures, vec![vec![ShaderDefVal::Int("TAPS".into(), 5)]]);
+
+        let shader = Shader::from_wgsl("#if 2 <= LEVEL && LEVEL != 7\n#endif");
+        assert_eq!(
+            explorer.referenced_defs(&shader).unwrap()[0].values,
+            vec![1, 2, 7]
+        );
+    }
+
+    #[test]
+    fn constraints_prune_permutations() {
+        let shader =
+            Shader::from_wgsl("#ifdef PCF\n#endif\n#ifdef VSM\n#endif\n#ifdef SOFT\n#endif");
+        let processor = ShaderProcessor::default();
+        let provider = HashMap::<ShaderImport, Shader>::new();
+        let set = |name: &str| ShaderDefVal::Bool(name.into(), true);
+
+        let explorer = PermutationExplorer::new(&processor, &provider)
+            .with_constraint(PermutationConstraint::Exclusive(vec![
+                "PCF".into(),
+                "VSM".into(),
+            ]))
+            .with_constraint(PermutationConstraint::Requires("SOFT".into(), "PCF".into()))
+            .with_constraint(PermutationConstraint::Fixed(ShaderDefVal::Int(
+                "MODE".into(),
+                2,
+            )));
+        let mode = ShaderDefVal::Int("MODE".into(), 2);
+        assert_eq!(
+            explorer.permutations(&shader).unwrap(),
+            vec![
+                vec![mode.clone()],
+                vec![mode.clone(), set("VSM")],
+                vec![mode.clone(), set("PCF")],
+                vec![mode, set("PCF"), set("SOFT")],
+            ]
+        );
+
+        let explorer = PermutationExplorer::new(&processor, &provider)
+            .with_constraint(PermutationConstraint::Unset("PCF".into()))
+            .with_constraint(PermutationConstraint::Unset("SOFT".into()))
+            .with_constraint(PermutationConstraint::Values(vec![
+                ShaderDefVal::Int("VSM".into(), 1),
+                ShaderDefVal::Int("VSM".into(), 2),
+            ]));
+        assert_eq!(
+            explorer.permutations(&shader).unwrap(),
+            vec![
+                vec![ShaderDefVal::Int("VSM".into(), 1)],
+                vec![ShaderDefVal::Int("VSM".into(), 2)],
+            ]
+        );
+    }
+
+    #[test]
+    fn permutation_count_is_limited() {
+        let shader = Shader::from_wgsl("#ifdef A\n#endif\n#ifdef B\n#endif\n#ifdef C\n#endif");
+        let processor = ShaderProcessor::default();
+        let provider = HashMap::<ShaderImport, Shader>::new();
+        let explorer = PermutationExplorer::new(&processor, &provider).with_max_permutations(4);
+        assert!(matches!(
+            explorer.permutations(&shader),
+            Err(PermutationError::TooManyPermutations { count: 8, max: 4 })
+        ));
+        let explorer =
+            explorer.with_constraint(PermutationConstraint::Fixed(ShaderDefVal::from("A")));
+        assert_eq!(explorer.permutations(&shader).unwrap().len(), 4);
+    }
+
+    #[test]
+    fn substituted_defs_need_constraints() {
+        let shader = Shader::from_wgsl("fn taps() -> i32 { return #{TAPS}; }");
+        let processor = ShaderProcessor::default();
+        let provider = HashMap::<ShaderImport, Shader>::new();
+        let explorer = PermutationExplorer::new(&processor, &provider);
+        assert!(matches!(
+            explorer.permutations(&shader),
+            Err(PermutationError::UnconstrainedSubstitution(defs)) if defs == ["TAPS"]
+        ));
+
+        let taps = ShaderDefVal::Int("TAPS".into(), 4);
+        let explorer = explorer.with_constraint(PermutationConstraint::Fixed(taps.clone()));
+        let report = explorer.explore(&shader).unwrap();
+        assert!(report.referenced_defs[0].substitution_only);
+        assert_eq!(report.results.len(), 1);
+        assert_eq!(report.results[0].shader_defs, vec![taps]);
+        assert!(report.is_valid());
+    }
+
+    #[test]
+    fn defs_are_collected_through_imports() {
+        let shader = Shader::from_wgsl(
+            "#ifdef SHADOWS\n#import lib::shadows\n#endif\nfn f() -> f32 { return 1.0; }",
+        );
+        let shadows = Shader::from_wgsl(
+            "#if TAPS >= 8\nfn taps() -> f32 { return missing; }\n#endif\nfn g() {}",
+        );
+        let processor = ShaderProcessor::default();
+        let provider = HashMap::from([(ShaderImport::Custom("lib::shadows".into()), shadows)]);
+        let explorer = PermutationExplorer::new(&processor, &provider);
+
+        let referenced = explorer.referenced_defs(&shader).unwrap();
+        assert_eq!(
+            referenced,
+            vec![
+                ReferencedDef {
+                    name: "SHADOWS".into(),
+                    values: vec![],
+                    substitution_only: false,
+                },
+                ReferencedDef {
+                    name: "TAPS".into(),
+                    values: vec![7, 8],
+                    substitution_only: false,
+                },
+            ]
+        );
+
+        let report = explorer.explore(&shader).unwrap();
+        let failures = report
+            .failures()
+            .map(|result| result.shader_defs.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            failures,
+            vec![vec![
+                ShaderDefVal::Bool("SHADOWS".into(), true),
+                ShaderDefVal::Int("TAPS".into(), 8),
+            ]]
+        );
+    }
+}
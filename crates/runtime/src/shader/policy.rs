@@ -0,0 +1,381 @@
+use std::path::Path;
+
+use super::{asset_path, ProcessShaderError};
+
+/// Limits on what imports may refer to and how much they may expand to.
+///
+/// The default policy allows everything. [`ImportPolicy::sandboxed`] is meant for processing
+/// untrusted shaders: imports are confined to the loader's roots and the expanded output is capped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImportPolicy {
+    allow_absolute_paths: bool,
+    allow_root_escapes: bool,
+    allow_symlink_escapes: bool,
+    max_expanded_size: Option<usize>,
+    max_import_count: Option<usize>,
+}
+
+impl Default for ImportPolicy {
+    fn default() -> Self {
+        Self {
+            allow_absolute_paths: true,
+            allow_root_escapes: true,
+            allow_symlink_escapes: true,
+            max_expanded_size: None,
+            max_import_count: None,
+        }
+    }
+}
+
+impl ImportPolicy {
+    pub const SANDBOXED_MAX_EXPANDED_SIZE: usize = 1 << 20;
+    pub const SANDBOXED_MAX_IMPORT_COUNT: usize = 256;
+
+    /// Rejects absolute import paths, paths and symlinks leading outside the roots, and caps the
+    /// expanded size and import count at [`SANDBOXED_MAX_EXPANDED_SIZE`](Self::SANDBOXED_MAX_EXPANDED_SIZE)
+    /// and [`SANDBOXED_MAX_IMPORT_COUNT`](Self::SANDBOXED_MAX_IMPORT_COUNT).
+    pub fn sandboxed() -> Self {
+        Self {
+            allow_absolute_paths: false,
+            allow_root_escapes: false,
+            allow_symlink_escapes: false,
+            max_expanded_size: Some(Self::SANDBOXED_MAX_EXPANDED_SIZE),
+            max_import_count: Some(Self::SANDBOXED_MAX_IMPORT_COUNT),
+        }
+    }
+
+    /// Sets whether `#import "/absolute/path"` is allowed. Otherwise it fails with
+    /// [`ProcessShaderError::AbsoluteImportPath`].
+    pub fn set_allow_absolute_paths(&mut self, allow_absolute_paths: bool) {
+        self.allow_absolute_paths = allow_absolute_paths;
+    }
+
+    pub fn with_allow_absolute_paths(mut self, allow_absolute_paths: bool) -> Self {
+        self.set_allow_absolute_paths(allow_absolute_paths);
+        self
+    }
+
+    /// Sets whether an import may use `..` to leave its root directory. Otherwise it fails with
+    /// [`ProcessShaderError::ImportEscapesRoot`].
+    pub fn set_allow_root_escapes(&mut self, allow_root_escapes: bool) {
+        self.allow_root_escapes = allow_root_escapes;
+    }
+
+    pub fn with_allow_root_escapes(mut self, allow_root_escapes: bool) -> Self {
+        self.set_allow_root_escapes(allow_root_escapes);
+        self
+    }
+
+    /// Sets whether a loaded file may be a symlink to a file outside its root directory. Otherwise
+    /// loading it fails with [`ProcessShaderError::SymlinkEscapesRoot`].
+    pub fn set_allow_symlink_escapes(&mut self, allow_symlink_escapes: bool) {
+        self.allow_symlink_escapes = allow_symlink_escapes;
+    }
+
+    pub fn with_allow_symlink_escapes(mut self, allow_symlink_escapes: bool) -> Self {
+        self.set_allow_symlink_escapes(allow_symlink_escapes);
+        self
+    }
+
+    /// Caps the size in bytes of a loaded file and of a processed shader including its imports.
+    /// Larger ones fail with [`ProcessShaderError::ExpandedSizeTooLarge`] as soon as the output
+    /// grows past the cap, not after it's complete.
+    pub fn set_max_expanded_size(&mut self, max_expanded_size: Option<usize>) {
+        self.max_expanded_size = max_expanded_size;
+    }
+
+    pub fn with_max_expanded_size(mut self, max_expanded_size: Option<usize>) -> Self {
+        self.set_max_expanded_size(max_expanded_size);
+        self
+    }
+
+    /// Caps how many imports a single processing run may expand. More fail with
+    /// [`ProcessShaderError::TooManyImports`].
+    pub fn set_max_import_count(&mut self, max_import_count: Option<usize>) {
+        self.max_import_count = max_import_count;
+    }
+
+    pub fn with_max_import_count(mut self, max_import_count: Option<usize>) -> Self {
+        self.set_max_import_count(max_import_count);
+        self
+    }
+
+    #[inline]
+    pub fn allows_symlink_escapes(&self) -> bool {
+        self.allow_symlink_escapes
+    }
+
+    #[inline]
+    pub fn max_expanded_size(&self) -> Option<usize> {
+        self.max_expanded_size
+    }
+
+    #[inline]
+    pub fn max_import_count(&self) -> Option<usize> {
+        self.max_import_count
+    }
+
+    /// The normalized asset paths `#import "path"` may refer to, in the order they should be tried:
+    /// relative to the importing asset, then relative to the roots.
+    pub(super) fn asset_path_candidates(
+        &self,
+        importer: Option<&str>,
+        path: &str,
+    ) -> Result<Vec<String>, ProcessShaderError> {
+        if !self.allow_absolute_paths && is_absolute(path) {
+            return Err(ProcessShaderError::AbsoluteImportPath(path.to_string()));
+        }
+        let candidates = importer
+            .map(|importer| asset_path::join_relative(importer, path))
+            .into_iter()
+            .chain(Some(asset_path::normalize(path)))
+            .filter(|candidate| self.allow_root_escapes || !escapes_root(candidate))
+            .collect::<Vec<_>>();
+        if candidates.is_empty() {
+            return Err(ProcessShaderError::ImportEscapesRoot(path.to_string()));
+        }
+        Ok(candidates)
+    }
+
+    pub(super) fn check_expanded_size(&self, size: usize) -> Result<(), ProcessShaderError> {
+        match self.max_expanded_size {
+            Some(max_size) if size > max_size => {
+                Err(ProcessShaderError::ExpandedSizeTooLarge { size, max_size })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub(super) fn check_import_count(&self, count: usize) -> Result<(), ProcessShaderError> {
+        match self.max_import_count {
+            Some(max_count) if count > max_count => {
+                Err(ProcessShaderError::TooManyImports { max_count })
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+fn is_absolute(path: &str) -> bool {
+    path.starts_with(['/', '\\']) || Path::new(path).is_absolute()
+}
+
+/// Whether the normalized asset path leaves its root with `..`.
+pub(super) fn escapes_root(normalized: &str) -> bool {
+    normalized == ".." || normalized.starts_with("../")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, fs, path::PathBuf, process};
+
+    use super::*;
+    use crate::shader::{
+        Shader, ShaderImport, ShaderLibrary, ShaderLoadError, ShaderLoader, ShaderProcessor,
+        ShaderSourceProvider,
+    };
+
+    /// A directory with a `root` for the loader and a file `outside` of it, removed when dropped.
+    struct Fixture {
+        dir: PathBuf,
+    }
+
+    impl Fixture {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("policy-{}-{}", name, process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(dir.join("root/lib")).unwrap();
+            fs::write(dir.join("root/common.wgsl"), "fn common() {}").unwrap();
+            fs::write(dir.join("outside.wgsl"), "fn secret() {}").unwrap();
+            Self { dir }
+        }
+
+        fn root(&self) -> PathBuf {
+            self.dir.join("root")
+        }
+
+        fn loader(&self) -> ShaderLoader {
+            ShaderLoader::new(self.root()).with_policy(ImportPolicy::sandboxed())
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn process(
+        policy: ImportPolicy,
+        shader: &Shader,
+        provider: &dyn ShaderSourceProvider,
+    ) -> Result<String, ProcessShaderError> {
+        let processed = ShaderProcessor::default()
+            .with_policy(policy)
+            .process_with_provider(shader, &[], provider)?;
+        Ok(processed.get_wgsl_source().unwrap().to_string())
+    }
+
+    fn importer(asset_path: &str, import: &str) -> Shader {
+        Shader::from_wgsl(format!("#import \"{}\"", import)).with_asset_path(asset_path)
+    }
+
+    #[test]
+    fn relative_imports_stay_in_the_root() {
+        let fixture = Fixture::new("relative");
+        let loader = fixture.loader();
+
+        // Relative to the importer this stays in the root, relative to the root it wouldn't.
+        let shader = importer("lib/main.wgsl", "../common.wgsl");
+        assert!(process(ImportPolicy::sandboxed(), &shader, &loader)
+            .unwrap()
+            .contains("fn common()"));
+
+        for (asset_path, import) in [
+            ("lib/main.wgsl", "../../outside.wgsl"),
+            ("main.wgsl", "../outside.wgsl"),
+            ("main.wgsl", "lib/../../outside.wgsl"),
+        ] {
+            assert_eq!(
+                process(
+                    ImportPolicy::sandboxed(),
+                    &importer(asset_path, import),
+                    &loader
+                ),
+                Err(ProcessShaderError::ImportEscapesRoot(import.to_string()))
+            );
+        }
+        match loader.load(&mut ShaderLibrary::default(), "../outside.wgsl") {
+            Err(ShaderLoadError::Process(err)) => assert_eq!(
+                err,
+                ProcessShaderError::ImportEscapesRoot("../outside.wgsl".into())
+            ),
+            result => panic!("expected a root escape, got {:?}", result),
+        }
+
+        let permissive = ImportPolicy::sandboxed().with_allow_root_escapes(true);
+        let shader = importer("main.wgsl", "../outside.wgsl");
+        let loader = fixture.loader().with_policy(permissive.clone());
+        assert!(process(permissive, &shader, &loader)
+            .unwrap()
+            .contains("fn secret()"));
+    }
+
+    #[test]
+    fn absolute_imports_are_rejected() {
+        let fixture = Fixture::new("absolute");
+        let absolute = fixture.dir.join("outside.wgsl");
+        let absolute = absolute.to_str().unwrap();
+        let shader = importer("main.wgsl", absolute);
+        assert_eq!(
+            process(ImportPolicy::sandboxed(), &shader, &fixture.loader()),
+            Err(ProcessShaderError::AbsoluteImportPath(absolute.to_string()))
+        );
+        assert_eq!(
+            process(
+                ImportPolicy::sandboxed(),
+                &importer("main.wgsl", "/etc/passwd"),
+                &fixture.loader()
+            ),
+            Err(ProcessShaderError::AbsoluteImportPath("/etc/passwd".into()))
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_out_of_the_root_are_rejected() {
+        let fixture = Fixture::new("symlink");
+        let outside = fixture.dir.join("outside.wgsl");
+        std::os::unix::fs::symlink(&outside, fixture.root().join("link.wgsl")).unwrap();
+        std::os::unix::fs::symlink(
+            fixture.root().join("common.wgsl"),
+            fixture.root().join("lib/inside.wgsl"),
+        )
+        .unwrap();
+        let escape = ProcessShaderError::SymlinkEscapesRoot {
+            asset_path: "link.wgsl".into(),
+            target: outside.canonicalize().unwrap(),
+        };
+
+        let shader = importer("main.wgsl", "link.wgsl");
+        assert_eq!(
+            process(ImportPolicy::sandboxed(), &shader, &fixture.loader()),
+            Err(escape.clone())
+        );
+        match fixture
+            .loader()
+            .load(&mut ShaderLibrary::default(), "link.wgsl")
+        {
+            Err(ShaderLoadError::Process(err)) => assert_eq!(err, escape),
+            result => panic!("expected a symlink escape, got {:?}", result),
+        }
+
+        // Symlinks within the root are fine.
+        let shader = importer("main.wgsl", "lib/inside.wgsl");
+        assert!(
+            process(ImportPolicy::sandboxed(), &shader, &fixture.loader())
+                .unwrap()
+                .contains("fn common()")
+        );
+    }
+
+    #[test]
+    fn expanded_size_is_capped() {
+        let fixture = Fixture::new("size");
+        fs::write(
+            fixture.root().join("large.wgsl"),
+            "// padding\n".repeat(100),
+        )
+        .unwrap();
+        let policy = ImportPolicy::sandboxed().with_max_expanded_size(Some(500));
+        let loader = fixture.loader().with_policy(policy.clone());
+
+        // The loader checks the file size before reading it.
+        match loader.load(&mut ShaderLibrary::default(), "large.wgsl") {
+            Err(ShaderLoadError::Process(err)) => assert_eq!(
+                err,
+                ProcessShaderError::ExpandedSizeTooLarge {
+                    size: 1100,
+                    max_size: 500
+                }
+            ),
+            result => panic!("expected a size error, got {:?}", result),
+        }
+
+        // Each import is small, but together they expand past the cap.
+        let small = Shader::from_wgsl("// padding\n".repeat(20));
+        let mut imports = HashMap::new();
+        let mut source = String::new();
+        for i in 0..5 {
+            imports.insert(ShaderImport::Custom(format!("small{}", i)), small.clone());
+            source.push_str(&format!("#import small{}\n", i));
+        }
+        let err = process(policy, &Shader::from_wgsl(source), &imports).unwrap_err();
+        assert!(
+            matches!(err, ProcessShaderError::ExpandedSizeTooLarge { size, max_size: 500 } if size > 500),
+            "{:?}",
+            err
+        );
+    }
+
+    #[test]
+    fn import_count_is_capped() {
+        let mut imports = HashMap::new();
+        let mut source = String::new();
+        for i in 0..3 {
+            imports.insert(
+                ShaderImport::Custom(format!("module{}", i)),
+                Shader::from_wgsl(format!("fn f{}() {{}}", i)),
+            );
+            source.push_str(&format!("#import module{}\n", i));
+        }
+        let shader = Shader::from_wgsl(source);
+        let policy = |max_count| ImportPolicy::sandboxed().with_max_import_count(Some(max_count));
+
+        assert!(process(policy(3), &shader, &imports).is_ok());
+        assert_eq!(
+            process(policy(2), &shader, &imports),
+            Err(ProcessShaderError::TooManyImports { max_count: 2 })
+        );
+    }
+}
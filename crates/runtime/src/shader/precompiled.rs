@@ -0,0 +1,609 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    str,
+};
+
+use thiserror::Error;
+
+use super::{
+    normalize_shader_defs, permutations::collect_referenced_defs, visit_import_graph,
+    ProcessShaderError, Shader, ShaderDefVal, ShaderImport, ShaderProcessor, ShaderReflectError,
+    ShaderSourceProvider, Source, ValidationConfig,
+};
+
+#[derive(Debug, Error)]
+pub enum PrecompiledShaderError {
+    #[error("Not a precompiled shader file.")]
+    InvalidMagic,
+    #[error(
+        "Unsupported precompiled shader format version {0}. Expected version {}.",
+        PrecompiledShader::FORMAT_VERSION
+    )]
+    UnsupportedVersion(u32),
+    #[error("The precompiled shader data ends unexpectedly.")]
+    UnexpectedEnd,
+    #[error("The precompiled shader data is invalid: {0}.")]
+    Invalid(&'static str),
+}
+
+#[derive(Debug, Error)]
+pub enum PrecompileError {
+    #[error(transparent)]
+    Process(#[from] ProcessShaderError),
+    #[error(
+        "Shaders from naga IR can't be precompiled, since naga IR has no stable encoding to hash."
+    )]
+    NagaModuleSource,
+    #[error("Failed to process the permutation {shader_defs:?}: {source}")]
+    PermutationProcess {
+        shader_defs: Vec<ShaderDefVal>,
+        #[source]
+        source: ProcessShaderError,
+    },
+    #[error("Failed to reflect the permutation {shader_defs:?}: {source}")]
+    PermutationReflect {
+        shader_defs: Vec<ShaderDefVal>,
+        #[source]
+        source: Box<ShaderReflectError>,
+    },
+    #[error("Failed to convert the permutation {shader_defs:?} to SPIR-V: {source}")]
+    SpirVConversion {
+        shader_defs: Vec<ShaderDefVal>,
+        #[source]
+        source: naga::back::spv::Error,
+    },
+}
+
+/// SPIR-V blobs for a fixed set of shader permutations, compiled ahead of time so the shader
+/// sources don't need to be shipped.
+///
+/// Each blob is stored under the defs it was processed with. Processing a
+/// [`Shader::from_precompiled_spirv`] picks the blob for the given defs, and fails with
+/// [`ProcessShaderError::PermutationNotPrecompiled`] if there is none. Defs set to `false` count
+/// as unset, like they do in `#ifdef`, and if the [`referenced_defs`](Self::referenced_defs) are
+/// known, other defs are ignored, so a global def set finds the right blob.
+///
+/// The [`source_hash`](Self::source_hash) identifies the sources the blobs were built from, so a
+/// build step can tell when they're stale. See [`PrecompiledShaderBuilder::source_hash`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PrecompiledShader {
+    source_hash: u64,
+    referenced_defs: Option<BTreeSet<String>>,
+    permutations: HashMap<Vec<ShaderDefVal>, Vec<u32>>,
+}
+
+impl PrecompiledShader {
+    /// The version of the format written by [`to_bytes`](Self::to_bytes). Files with another
+    /// version are rejected by [`from_bytes`](Self::from_bytes).
+    pub const FORMAT_VERSION: u32 = 1;
+    const MAGIC: [u8; 4] = *b"SPVM";
+
+    pub fn new(source_hash: u64) -> Self {
+        Self {
+            source_hash,
+            referenced_defs: None,
+            permutations: HashMap::new(),
+        }
+    }
+
+    #[inline]
+    pub fn source_hash(&self) -> u64 {
+        self.source_hash
+    }
+
+    /// Sets the names of the defs the shader and its imports consult. Other defs are dropped from
+    /// the def lists blobs are stored and looked up with. Must be set before inserting blobs.
+    pub fn set_referenced_defs(&mut self, referenced_defs: Option<BTreeSet<String>>) {
+        self.referenced_defs = referenced_defs;
+    }
+
+    pub fn with_referenced_defs(mut self, referenced_defs: Option<BTreeSet<String>>) -> Self {
+        self.set_referenced_defs(referenced_defs);
+        self
+    }
+
+    /// The names of the defs the shader and its imports consult, if known.
+    #[inline]
+    pub fn referenced_defs(&self) -> Option<&BTreeSet<String>> {
+        self.referenced_defs.as_ref()
+    }
+
+    /// The def list a blob for `shader_defs` is stored and looked up under: sorted by name, with
+    /// only the last def per name, and without defs set to `false` or that the shader doesn't
+    /// consult.
+    pub fn permutation_defs(&self, shader_defs: &[ShaderDefVal]) -> Vec<ShaderDefVal> {
+        let mut shader_defs = normalize_shader_defs(shader_defs);
+        shader_defs.retain(|def| {
+            def.is_defined()
+                && match &self.referenced_defs {
+                    Some(names) => names.contains(def.name()),
+                    None => true,
+                }
+        });
+        shader_defs
+    }
+
+    /// Stores the SPIR-V for `shader_defs`, replacing any blob stored for an equivalent def list.
+    pub fn insert(&mut self, shader_defs: &[ShaderDefVal], spirv: Vec<u32>) {
+        self.permutations
+            .insert(self.permutation_defs(shader_defs), spirv);
+    }
+
+    /// The SPIR-V for `shader_defs`. The order of the defs doesn't matter, and later defs override
+    /// earlier ones with the same name.
+    pub fn get(&self, shader_defs: &[ShaderDefVal]) -> Option<&[u32]> {
+        self.permutations
+            .get(&self.permutation_defs(shader_defs))
+            .map(Vec::as_slice)
+    }
+
+    /// The stored permutations, with their defs sorted by name.
+    pub fn iter(&self) -> impl Iterator<Item = (&[ShaderDefVal], &[u32])> {
+        self.permutations
+            .iter()
+            .map(|(shader_defs, spirv)| (shader_defs.as_slice(), spirv.as_slice()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.permutations.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.permutations.is_empty()
+    }
+
+    /// Encodes the permutations in the versioned `.spvm` format read by
+    /// [`from_bytes`](Self::from_bytes). All integers are little-endian:
+    ///
+    /// ```text
+    /// magic "SPVM", version: u32, source_hash: u64
+    /// referenced defs known: u8, referenced def count: u32, per def: name length: u32, UTF-8 name
+    /// permutation count: u32
+    /// per permutation: def count: u32, defs, word count: u32, SPIR-V words: u32...
+    /// per def: kind: u8 (0 bool, 1 int, 2 uint), name length: u32, UTF-8 name, value: u8/i32/u32
+    /// ```
+    ///
+    /// Permutations and referenced defs are sorted, so the same permutations always encode the same
+    /// way.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut permutations = self.permutations.iter().collect::<Vec<_>>();
+        permutations.sort_by(|(a, _), (b, _)| def_sort_key(a).cmp(&def_sort_key(b)));
+
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&Self::MAGIC);
+        bytes.extend_from_slice(&Self::FORMAT_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&self.source_hash.to_le_bytes());
+        bytes.push(self.referenced_defs.is_some() as u8);
+        let referenced_defs = self.referenced_defs.iter().flatten().collect::<Vec<_>>();
+        write_len(&mut bytes, referenced_defs.len());
+        for name in referenced_defs {
+            write_len(&mut bytes, name.len());
+            bytes.extend_from_slice(name.as_bytes());
+        }
+        write_len(&mut bytes, permutations.len());
+        for (shader_defs, spirv) in permutations {
+            write_len(&mut bytes, shader_defs.len());
+            for def in shader_defs {
+                let (kind, value) = match def {
+                    ShaderDefVal::Bool(_, value) => (0u8, vec![*value as u8]),
+                    ShaderDefVal::Int(_, value) => (1, value.to_le_bytes().to_vec()),
+                    ShaderDefVal::UInt(_, value) => (2, value.to_le_bytes().to_vec()),
+                };
+                bytes.push(kind);
+                write_len(&mut bytes, def.name().len());
+                bytes.extend_from_slice(def.name().as_bytes());
+                bytes.extend_from_slice(&value);
+            }
+            write_len(&mut bytes, spirv.len());
+            for word in spirv {
+                bytes.extend_from_slice(&word.to_le_bytes());
+            }
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, PrecompiledShaderError> {
+        let mut reader = Reader(bytes);
+        if reader.take(4)? != Self::MAGIC {
+            return Err(PrecompiledShaderError::InvalidMagic);
+        }
+        let version = reader.u32()?;
+        if version != Self::FORMAT_VERSION {
+            return Err(PrecompiledShaderError::UnsupportedVersion(version));
+        }
+        let mut precompiled = Self::new(reader.u64()?);
+        let known = reader.take(1)?[0] != 0;
+        let referenced_defs = (0..reader.u32()?)
+            .map(|_| reader.name())
+            .collect::<Result<BTreeSet<_>, _>>()?;
+        precompiled.set_referenced_defs(known.then_some(referenced_defs));
+        for _ in 0..reader.u32()? {
+            let mut shader_defs = Vec::new();
+            for _ in 0..reader.u32()? {
+                let kind = reader.take(1)?[0];
+                let name = reader.name()?;
+                shader_defs.push(match kind {
+                    0 => ShaderDefVal::Bool(name, reader.take(1)?[0] != 0),
+                    1 => ShaderDefVal::Int(name, reader.u32()? as i32),
+                    2 => ShaderDefVal::UInt(name, reader.u32()?),
+                    _ => return Err(PrecompiledShaderError::Invalid("unknown def kind")),
+                });
+            }
+            let word_count = reader.u32()? as usize;
+            let spirv = (0..word_count)
+                .map(|_| reader.u32())
+                .collect::<Result<Vec<_>, _>>()?;
+            precompiled.insert(&shader_defs, spirv);
+        }
+        if !reader.0.is_empty() {
+            return Err(PrecompiledShaderError::Invalid("trailing data"));
+        }
+        Ok(precompiled)
+    }
+}
+
+fn def_sort_key(shader_defs: &[ShaderDefVal]) -> Vec<(&str, String)> {
+    shader_defs
+        .iter()
+        .map(|def| (def.name(), def.value_as_string()))
+        .collect()
+}
+
+fn write_len(bytes: &mut Vec<u8>, len: usize) {
+    let len = u32::try_from(len).expect("precompiled shader sections are limited to u32::MAX");
+    bytes.extend_from_slice(&len.to_le_bytes());
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], PrecompiledShaderError> {
+        if self.0.len() < len {
+            return Err(PrecompiledShaderError::UnexpectedEnd);
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn u32(&mut self) -> Result<u32, PrecompiledShaderError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, PrecompiledShaderError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    /// A def name, prefixed with its length.
+    fn name(&mut self) -> Result<String, PrecompiledShaderError> {
+        let len = self.u32()? as usize;
+        str::from_utf8(self.take(len)?)
+            .map(String::from)
+            .map_err(|_| PrecompiledShaderError::Invalid("def name is not UTF-8"))
+    }
+}
+
+/// Processes a shader with a list of permutations and compiles each one to SPIR-V.
+///
+/// ```ignore
+/// let explorer = PermutationExplorer::new(library.processor(), &library);
+/// let permutations = explorer.permutations(&shader)?;
+/// let precompiled = PrecompiledShaderBuilder::new(library.processor(), &library)
+///     .build(&shader, &permutations)?;
+/// fs::write("pbr.spvm", precompiled.to_bytes())?;
+/// ```
+pub struct PrecompiledShaderBuilder<'a> {
+    processor: &'a ShaderProcessor,
+    provider: &'a dyn ShaderSourceProvider,
+    validation: ValidationConfig,
+}
+
+impl<'a> PrecompiledShaderBuilder<'a> {
+    pub fn new(processor: &'a ShaderProcessor, provider: &'a dyn ShaderSourceProvider) -> Self {
+        Self {
+            processor,
+            provider,
+            validation: ValidationConfig::default(),
+        }
+    }
+
+    /// Sets how each permutation is validated before it's compiled to SPIR-V.
+    pub fn set_validation(&mut self, validation: ValidationConfig) {
+        self.validation = validation;
+    }
+
+    pub fn with_validation(mut self, validation: ValidationConfig) -> Self {
+        self.set_validation(validation);
+        self
+    }
+
+    /// A hash of the sources of `shader` and every shader it may import. Unlike
+    /// [`std::hash::Hash`], it's stable across builds and platforms, so it can be compared with
+    /// the [`PrecompiledShader::source_hash`] of a file built earlier.
+    ///
+    /// Fails with [`PrecompileError::NagaModuleSource`] if one of the shaders is naga IR, whose
+    /// maps have no stable order to hash.
+    pub fn source_hash(&self, shader: &Shader) -> Result<u64, PrecompileError> {
+        let mut hasher = Fnv1a::default();
+        let mut naga_module = false;
+        visit_import_graph(
+            shader,
+            self.provider,
+            self.processor.policy(),
+            &mut |import, shader| {
+                match import {
+                    None => hasher.write_u8(0),
+                    Some(ShaderImport::AssetPath(path)) => {
+                        hasher.write_u8(1);
+                        hasher.write_bytes(path.as_bytes());
+                    }
+                    Some(ShaderImport::Custom(path)) => {
+                        hasher.write_u8(2);
+                        hasher.write_bytes(path.as_bytes());
+                    }
+                }
+                match &shader.source {
+                    Source::Wgsl(source) => {
+                        hasher.write_u8(0);
+                        hasher.write_bytes(source.as_bytes());
+                    }
+                    Source::Glsl(source, stage) => {
+                        hasher.write_u8(1);
+                        hasher.write_u8(*stage as u8);
+                        hasher.write_bytes(source.as_bytes());
+                    }
+                    Source::SpirV(source) => {
+                        hasher.write_u8(2);
+                        hasher.write_bytes(source);
+                    }
+                    Source::PrecompiledSpirVMacros(precompiled) => {
+                        hasher.write_u8(3);
+                        hasher.write_bytes(&precompiled.to_bytes());
+                    }
+                    Source::NagaModule(_) => naga_module = true,
+                }
+                Ok(())
+            },
+        )?;
+        if naga_module {
+            return Err(PrecompileError::NagaModuleSource);
+        }
+        Ok(hasher.0)
+    }
+
+    /// Processes `shader` with each def list in `permutations`, validates the result and compiles
+    /// it to SPIR-V. The defs `shader` and its imports consult are stored with the blobs.
+    pub fn build(
+        &self,
+        shader: &Shader,
+        permutations: &[Vec<ShaderDefVal>],
+    ) -> Result<PrecompiledShader, PrecompileError> {
+        let referenced_defs =
+            collect_referenced_defs(shader, self.provider, self.processor.policy())?;
+        let mut precompiled = PrecompiledShader::new(self.source_hash(shader)?)
+            .with_referenced_defs(Some(referenced_defs.into_keys().collect()));
+        for shader_defs in permutations {
+            let processed = self
+                .processor
+                .process_with_provider(shader, shader_defs, self.provider)
+                .map_err(|source| PrecompileError::PermutationProcess {
+                    shader_defs: shader_defs.clone(),
+                    source,
+                })?;
+            let reflection = processed
+                .reflect_with_validation(&self.validation)
+                .map_err(|source| PrecompileError::PermutationReflect {
+                    shader_defs: shader_defs.clone(),
+                    source: Box::new(source),
+                })?;
+            let spirv =
+                reflection
+                    .get_spirv()
+                    .map_err(|source| PrecompileError::SpirVConversion {
+                        shader_defs: shader_defs.clone(),
+                        source,
+                    })?;
+            precompiled.insert(shader_defs, spirv);
+        }
+        Ok(precompiled)
+    }
+}
+
+/// 64-bit FNV-1a, which unlike the std hashers is guaranteed to stay the same.
+struct Fnv1a(u64);
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Fnv1a {
+    fn write_u8(&mut self, byte: u8) {
+        self.0 ^= byte as u64;
+        self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+    }
+
+    /// Writes the length first, so consecutive writes can't be confused with each other.
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        for byte in (bytes.len() as u64).to_le_bytes().iter().chain(bytes) {
+            self.write_u8(*byte);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{NagaModule, ProcessedShader};
+    use super::*;
+
+    fn precompiled() -> PrecompiledShader {
+        let referenced_defs = ["LIGHTS", "SHADOWS", "SCALE"].map(String::from);
+        let mut precompiled = PrecompiledShader::new(0x0123_4567_89ab_cdef)
+            .with_referenced_defs(Some(referenced_defs.into_iter().collect()));
+        precompiled.insert(&[], vec![0x0723_0203, 1]);
+        precompiled.insert(
+            &[
+                ShaderDefVal::Int("LIGHTS".into(), -4),
+                ShaderDefVal::Bool("SHADOWS".into(), true),
+                ShaderDefVal::UInt("SCALE".into(), u32::MAX),
+            ],
+            vec![0x0723_0203, 2, 3],
+        );
+        precompiled
+    }
+
+    #[test]
+    fn round_trip() {
+        let precompiled = precompiled();
+        let bytes = precompiled.to_bytes();
+        let decoded = PrecompiledShader::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded, precompiled);
+        assert_eq!(decoded.to_bytes(), bytes);
+        assert_eq!(
+            decoded.get(&[
+                ShaderDefVal::UInt("SCALE".into(), u32::MAX),
+                ShaderDefVal::Bool("SHADOWS".into(), true),
+                ShaderDefVal::Int("LIGHTS".into(), -4),
+                ShaderDefVal::Bool("UNUSED".into(), true),
+            ]),
+            Some(&[0x0723_0203, 2, 3][..])
+        );
+        assert_eq!(
+            decoded.get(&[ShaderDefVal::Bool("SHADOWS".into(), false)]),
+            Some(&[0x0723_0203, 1][..])
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_input() {
+        let bytes = precompiled().to_bytes();
+        for len in 4..bytes.len() {
+            assert!(
+                matches!(
+                    PrecompiledShader::from_bytes(&bytes[..len]),
+                    Err(PrecompiledShaderError::UnexpectedEnd)
+                ),
+                "truncated to {} bytes",
+                len
+            );
+        }
+        let mut trailing = bytes;
+        trailing.push(0);
+        assert!(matches!(
+            PrecompiledShader::from_bytes(&trailing),
+            Err(PrecompiledShaderError::Invalid("trailing data"))
+        ));
+    }
+
+    #[test]
+    fn rejects_bad_header() {
+        let mut bytes = precompiled().to_bytes();
+        for version in [0, PrecompiledShader::FORMAT_VERSION + 1] {
+            bytes[4..8].copy_from_slice(&version.to_le_bytes());
+            assert!(matches!(
+                PrecompiledShader::from_bytes(&bytes),
+                Err(PrecompiledShaderError::UnsupportedVersion(v)) if v == version
+            ));
+        }
+        bytes[..4].copy_from_slice(b"SPV\0");
+        assert!(matches!(
+            PrecompiledShader::from_bytes(&bytes),
+            Err(PrecompiledShaderError::InvalidMagic)
+        ));
+    }
+
+    const MAIN: &str = "#import consts
+[[stage(compute), workgroup_size(1)]]
+fn main() {
+#ifdef DOUBLE
+    let x = SCALE * 2.0;
+#else
+    let x = SCALE;
+#endif
+}";
+
+    fn provider(consts: &str) -> HashMap<ShaderImport, Shader> {
+        HashMap::from([(
+            ShaderImport::Custom("consts".into()),
+            Shader::from_wgsl(consts.to_string()),
+        )])
+    }
+
+    fn spirv_bytes(spirv: &[u32]) -> Vec<u8> {
+        spirv.iter().flat_map(|word| word.to_le_bytes()).collect()
+    }
+
+    #[test]
+    fn built_permutations_are_processed_from_their_blobs() {
+        let processor = ShaderProcessor::default();
+        let provider = provider("let SCALE: f32 = 2.0;");
+        let builder = PrecompiledShaderBuilder::new(&processor, &provider);
+        let shader = Shader::from_wgsl(MAIN);
+        let double = [ShaderDefVal::from("DOUBLE")];
+        let precompiled = builder.build(&shader, &[vec![], double.to_vec()]).unwrap();
+
+        assert_eq!(precompiled.len(), 2);
+        assert_eq!(
+            precompiled.source_hash(),
+            builder.source_hash(&shader).unwrap()
+        );
+        assert_eq!(
+            precompiled.referenced_defs(),
+            Some(&BTreeSet::from(["DOUBLE".to_string()]))
+        );
+        let single = precompiled.get(&[]).unwrap();
+        let doubled = precompiled.get(&double).unwrap();
+        assert_ne!(single, doubled);
+
+        let shader = Shader::from_precompiled_spirv(precompiled.clone());
+        let process = |shader_defs: &[ShaderDefVal]| {
+            processor.process_with_provider(&shader, shader_defs, &provider)
+        };
+        assert_eq!(
+            process(&[double[0].clone(), ShaderDefVal::from("UNUSED")]).unwrap(),
+            ProcessedShader::SpirV(spirv_bytes(doubled).into())
+        );
+        assert_eq!(
+            process(&[ShaderDefVal::Bool("DOUBLE".into(), false)]).unwrap(),
+            ProcessedShader::SpirV(spirv_bytes(single).into())
+        );
+        assert_eq!(
+            process(&[ShaderDefVal::Int("DOUBLE".into(), 2)]).unwrap_err(),
+            ProcessShaderError::PermutationNotPrecompiled(vec![ShaderDefVal::Int(
+                "DOUBLE".into(),
+                2
+            )])
+        );
+    }
+
+    #[test]
+    fn source_hash_covers_imports() {
+        let processor = ShaderProcessor::default();
+        let shader = Shader::from_wgsl(MAIN);
+        let hash = |consts: &str| {
+            PrecompiledShaderBuilder::new(&processor, &provider(consts))
+                .source_hash(&shader)
+                .unwrap()
+        };
+        assert_eq!(hash("let SCALE: f32 = 2.0;"), hash("let SCALE: f32 = 2.0;"));
+        assert_ne!(hash("let SCALE: f32 = 2.0;"), hash("let SCALE: f32 = 3.0;"));
+    }
+
+    #[test]
+    fn naga_modules_are_not_precompiled() {
+        let processor = ShaderProcessor::default();
+        let provider = provider("");
+        let builder = PrecompiledShaderBuilder::new(&processor, &provider);
+        let module = naga::front::wgsl::parse_str("fn f() {}").unwrap();
+        let shader = Shader::from_naga_module(NagaModule::new(module));
+        assert!(matches!(
+            builder.source_hash(&shader),
+            Err(PrecompileError::NagaModuleSource)
+        ));
+        assert!(matches!(
+            builder.build(&shader, &[vec![]]),
+            Err(PrecompileError::NagaModuleSource)
+        ));
+    }
+}
@@ -0,0 +1,258 @@
+use std::{borrow::Cow, collections::HashMap};
+
+use super::{asset_path, ProcessShaderError, Shader, ShaderHandle, ShaderImport};
+
+/// Resolves the shaders that `#import` directives refer to.
+///
+/// [`ShaderProcessor::process_with_provider`](super::ShaderProcessor::process_with_provider) asks
+/// the provider for each import it expands, so shaders can be loaded lazily from any backing store.
+/// `#import "path"` directives are passed as a normalized [`ShaderImport::AssetPath`], possibly
+/// more than once when the path could be relative to the importing shader or to the roots.
+pub trait ShaderSourceProvider {
+    /// Returns the shader registered under `import`, or `None` if this provider doesn't have it.
+    fn resolve(&self, import: &ShaderImport)
+        -> Result<Option<Cow<'_, Shader>>, ProcessShaderError>;
+}
+
+impl<P: ShaderSourceProvider + ?Sized> ShaderSourceProvider for &P {
+    fn resolve(
+        &self,
+        import: &ShaderImport,
+    ) -> Result<Option<Cow<'_, Shader>>, ProcessShaderError> {
+        (**self).resolve(import)
+    }
+}
+
+impl<P: ShaderSourceProvider + ?Sized> ShaderSourceProvider for Box<P> {
+    fn resolve(
+        &self,
+        import: &ShaderImport,
+    ) -> Result<Option<Cow<'_, Shader>>, ProcessShaderError> {
+        (**self).resolve(import)
+    }
+}
+
+impl ShaderSourceProvider for HashMap<ShaderImport, Shader> {
+    fn resolve(
+        &self,
+        import: &ShaderImport,
+    ) -> Result<Option<Cow<'_, Shader>>, ProcessShaderError> {
+        Ok(self.get(import).map(Cow::Borrowed))
+    }
+}
+
+/// The shaders and import handles passed to [`ShaderProcessor::process`](super::ShaderProcessor::process).
+pub(super) struct HandleMaps<'a> {
+    pub(super) shaders: &'a HashMap<ShaderHandle, Shader>,
+    pub(super) import_handles: &'a HashMap<ShaderImport, ShaderHandle>,
+}
+
+impl ShaderSourceProvider for HandleMaps<'_> {
+    fn resolve(
+        &self,
+        import: &ShaderImport,
+    ) -> Result<Option<Cow<'_, Shader>>, ProcessShaderError> {
+        Ok(self
+            .import_handles
+            .get(import)
+            .and_then(|handle| self.shaders.get(handle))
+            .map(Cow::Borrowed))
+    }
+}
+
+/// Shaders compiled into the binary, typically with `include_str!`.
+///
+/// Each shader is importable by its asset path, as `#import "lighting/pbr.wgsl"`, and by the path
+/// declared with `#define_import_path`, if any. A shader added later replaces any earlier one
+/// registered under the same asset path or import path.
+///
+/// ```ignore
+/// let shaders = EmbeddedShaderProvider::new()
+///     .with_wgsl("lighting/pbr.wgsl", include_str!("shaders/lighting/pbr.wgsl"));
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct EmbeddedShaderProvider {
+    shaders: Vec<Shader>,
+    imports: HashMap<ShaderImport, usize>,
+}
+
+impl EmbeddedShaderProvider {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_wgsl(&mut self, asset_path: &str, source: &'static str) {
+        self.add(Shader::from_wgsl(source), asset_path);
+    }
+
+    pub fn with_wgsl(mut self, asset_path: &str, source: &'static str) -> Self {
+        self.add_wgsl(asset_path, source);
+        self
+    }
+
+    pub fn add_glsl(&mut self, asset_path: &str, source: &'static str, stage: naga::ShaderStage) {
+        self.add(Shader::from_glsl(source, stage), asset_path);
+    }
+
+    pub fn with_glsl(
+        mut self,
+        asset_path: &str,
+        source: &'static str,
+        stage: naga::ShaderStage,
+    ) -> Self {
+        self.add_glsl(asset_path, source, stage);
+        self
+    }
+
+    pub fn add_spirv(&mut self, asset_path: &str, source: &'static [u8]) {
+        self.add(Shader::from_spirv(source), asset_path);
+    }
+
+    pub fn with_spirv(mut self, asset_path: &str, source: &'static [u8]) -> Self {
+        self.add_spirv(asset_path, source);
+        self
+    }
+
+    fn add(&mut self, shader: Shader, asset_path: &str) {
+        let shader = shader.with_asset_path(asset_path);
+        let index = self.shaders.len();
+        let asset_path = ShaderImport::AssetPath(asset_path::normalize(asset_path));
+        for import in shader
+            .import_path()
+            .cloned()
+            .into_iter()
+            .chain(Some(asset_path))
+        {
+            self.imports.insert(import, index);
+        }
+        self.shaders.push(shader);
+    }
+}
+
+impl ShaderSourceProvider for EmbeddedShaderProvider {
+    fn resolve(
+        &self,
+        import: &ShaderImport,
+    ) -> Result<Option<Cow<'_, Shader>>, ProcessShaderError> {
+        Ok(self
+            .imports
+            .get(import)
+            .map(|&index| Cow::Borrowed(&self.shaders[index])))
+    }
+}
+
+/// Tries a list of providers in order and returns the first shader found, so for example shaders
+/// on disk can override the embedded ones during development.
+#[derive(Default)]
+pub struct ChainedShaderProvider<'a> {
+    providers: Vec<Box<dyn ShaderSourceProvider + 'a>>,
+}
+
+impl<'a> ChainedShaderProvider<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a provider that is tried after the ones added before it.
+    pub fn push(&mut self, provider: impl ShaderSourceProvider + 'a) {
+        self.providers.push(Box::new(provider));
+    }
+
+    pub fn with(mut self, provider: impl ShaderSourceProvider + 'a) -> Self {
+        self.push(provider);
+        self
+    }
+}
+
+impl ShaderSourceProvider for ChainedShaderProvider<'_> {
+    fn resolve(
+        &self,
+        import: &ShaderImport,
+    ) -> Result<Option<Cow<'_, Shader>>, ProcessShaderError> {
+        for provider in &self.providers {
+            if let Some(shader) = provider.resolve(import)? {
+                return Ok(Some(shader));
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::Source;
+    use super::*;
+
+    fn resolve_source(
+        provider: &dyn ShaderSourceProvider,
+        import: &ShaderImport,
+    ) -> Option<String> {
+        provider
+            .resolve(import)
+            .unwrap()
+            .map(|shader| match &shader.source {
+                Source::Wgsl(source) => source.to_string(),
+                _ => unreachable!(),
+            })
+    }
+
+    fn asset(path: &str) -> ShaderImport {
+        ShaderImport::AssetPath(path.to_string())
+    }
+
+    fn custom(path: &str) -> ShaderImport {
+        ShaderImport::Custom(path.to_string())
+    }
+
+    #[test]
+    fn embedded_shaders_resolve_by_asset_and_import_path() {
+        let source = "#define_import_path lighting::pbr\nfn pbr() {}";
+        let shaders = EmbeddedShaderProvider::new().with_wgsl("./lighting//pbr.wgsl", source);
+        assert_eq!(
+            resolve_source(&shaders, &asset("lighting/pbr.wgsl")).as_deref(),
+            Some(source)
+        );
+        assert_eq!(
+            resolve_source(&shaders, &custom("lighting::pbr")).as_deref(),
+            Some(source)
+        );
+        let shader = shaders.resolve(&custom("lighting::pbr")).unwrap().unwrap();
+        assert_eq!(shader.asset_path(), Some("lighting/pbr.wgsl"));
+        assert_eq!(resolve_source(&shaders, &asset("pbr.wgsl")), None);
+    }
+
+    #[test]
+    fn embedded_shaders_are_replaced_by_later_ones() {
+        let shaders = EmbeddedShaderProvider::new()
+            .with_wgsl("a.wgsl", "#define_import_path common\nfn a() {}")
+            .with_wgsl("a.wgsl", "fn a2() {}")
+            .with_wgsl("b.wgsl", "#define_import_path common\nfn b() {}");
+        assert_eq!(
+            resolve_source(&shaders, &asset("a.wgsl")).as_deref(),
+            Some("fn a2() {}")
+        );
+        assert_eq!(
+            resolve_source(&shaders, &custom("common")).as_deref(),
+            Some("#define_import_path common\nfn b() {}")
+        );
+    }
+
+    #[test]
+    fn chained_providers_are_tried_in_order() {
+        let overrides =
+            HashMap::from([(custom("common"), Shader::from_wgsl("fn overridden() {}"))]);
+        let embedded = EmbeddedShaderProvider::new()
+            .with_wgsl("common.wgsl", "#define_import_path common\nfn common() {}")
+            .with_wgsl("other.wgsl", "#define_import_path other\nfn other() {}");
+        let chained = ChainedShaderProvider::new().with(&overrides).with(embedded);
+        assert_eq!(
+            resolve_source(&chained, &custom("common")).as_deref(),
+            Some("fn overridden() {}")
+        );
+        assert_eq!(
+            resolve_source(&chained, &custom("other")).as_deref(),
+            Some("#define_import_path other\nfn other() {}")
+        );
+        assert_eq!(resolve_source(&chained, &custom("missing")), None);
+    }
+}
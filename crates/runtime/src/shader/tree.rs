@@ -0,0 +1,423 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    mem,
+    ops::Range,
+};
+
+use super::{
+    evaluate_if,
+    expr::{Comparison, Expr},
+    is_defined, ImportDirective, ProcessShaderError, ShaderDefVal, ShaderDefs,
+    SHADER_IMPORT_PROCESSOR,
+};
+
+/// How deeply `#if*` blocks can nest. The tree is walked recursively, so this bounds the stack
+/// usage on untrusted shaders.
+pub(super) const MAX_NESTING_DEPTH: usize = 256;
+
+/// The preprocessor structure of a shader's source: its text, conditional blocks, def directives
+/// and imports.
+///
+/// Parsing runs the directive regexes over every line and parses `#if` expressions once, when the
+/// [`Shader`](super::Shader) is created. Processing then only walks the tree for the given def set.
+#[derive(Debug, Clone, Default)]
+pub(super) struct ShaderTree {
+    nodes: Vec<Node>,
+    /// The path of the first `#define_import_path` directive, in any block.
+    declared_import_path: Option<String>,
+    /// Whether there's an `#allow_multiple_imports` directive, in any block.
+    allow_multiple_imports: bool,
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+    /// One or more consecutive lines without directives or substitutions, excluding the line break
+    /// after the last one.
+    Text(Range<usize>),
+    /// A line containing `#{NAME}` substitutions, excluding its line break.
+    Substitute(Range<usize>),
+    /// An `#if`/`#ifdef`/`#ifndef` block. The first branch whose condition holds is taken.
+    Conditional(Vec<Branch>),
+    Define(ShaderDefVal),
+    Undef(String),
+    Import(ImportDirective),
+    /// A directive that failed to parse. Like any other directive it's only reported when reached.
+    Invalid(ProcessShaderError),
+}
+
+#[derive(Debug, Clone)]
+struct Branch {
+    condition: Condition,
+    nodes: Vec<Node>,
+}
+
+#[derive(Debug, Clone)]
+enum Condition {
+    If(Expr),
+    /// An `#if` or `#elif` whose expression failed to parse. It's reported when the branch is
+    /// reached.
+    Invalid(ProcessShaderError),
+    Defined(String),
+    NotDefined(String),
+    /// An `#else` branch.
+    Always,
+}
+
+impl Condition {
+    fn parse_if(expression: &str) -> Self {
+        match Expr::parse(expression.trim()) {
+            Ok(expr) => Condition::If(expr),
+            Err(err) => Condition::Invalid(err),
+        }
+    }
+
+    fn evaluate(&self, shader_defs: &ShaderDefs) -> Result<bool, ProcessShaderError> {
+        match self {
+            Condition::If(expr) => Ok(evaluate_if(expr, shader_defs)),
+            Condition::Invalid(err) => Err(err.clone()),
+            Condition::Defined(name) => Ok(is_defined(name, shader_defs)),
+            Condition::NotDefined(name) => Ok(!is_defined(name, shader_defs)),
+            Condition::Always => Ok(true),
+        }
+    }
+}
+
+/// A part of the output reached while evaluating a [`ShaderTree`].
+pub(super) enum Segment<'a> {
+    /// Text to emit as is, followed by a line break.
+    Text(&'a str),
+    /// A line to emit after substituting its `#{NAME}` occurrences, followed by a line break.
+    Substitute(&'a str),
+    Import(&'a ImportDirective),
+}
+
+/// A conditional block that is still open while parsing.
+struct OpenBlock {
+    branches: Vec<Branch>,
+    condition: Condition,
+    nodes: Vec<Node>,
+    in_else: bool,
+}
+
+impl OpenBlock {
+    /// Opens a block inside the innermost one in `blocks`, or at the top level.
+    fn open(blocks: &mut Vec<OpenBlock>, condition: Condition) -> Result<(), ProcessShaderError> {
+        if blocks.len() == MAX_NESTING_DEPTH {
+            return Err(ProcessShaderError::ConditionalsTooDeep(MAX_NESTING_DEPTH));
+        }
+        blocks.push(Self {
+            branches: Vec::new(),
+            condition,
+            nodes: Vec::new(),
+            in_else: false,
+        });
+        Ok(())
+    }
+
+    /// Closes the current branch and starts one for `directive`, which is `#else` or an `#elif*`.
+    fn next_branch(
+        blocks: &mut [OpenBlock],
+        directive: &str,
+        condition: Condition,
+    ) -> Result<(), ProcessShaderError> {
+        let is_else = directive == "else";
+        let block = match blocks.last_mut() {
+            Some(block) => block,
+            None if is_else => return Err(ProcessShaderError::ElseWithoutIf),
+            None => return Err(ProcessShaderError::ElifWithoutIf(directive.to_string())),
+        };
+        match block.in_else {
+            true if is_else => return Err(ProcessShaderError::ElseAfterElse),
+            true => return Err(ProcessShaderError::ElifAfterElse(directive.to_string())),
+            false => {}
+        }
+        let previous = mem::replace(&mut block.condition, condition);
+        block.branches.push(Branch {
+            condition: previous,
+            nodes: mem::take(&mut block.nodes),
+        });
+        block.in_else = is_else;
+        Ok(())
+    }
+
+    fn finish(mut self) -> Node {
+        self.branches.push(Branch {
+            condition: self.condition,
+            nodes: self.nodes,
+        });
+        Node::Conditional(self.branches)
+    }
+}
+
+impl ShaderTree {
+    pub(super) fn parse(source: &str) -> Result<Self, ProcessShaderError> {
+        Self::parse_with_defines(source, true)
+    }
+
+    /// Like [`parse`](Self::parse), but keeps `#define` and `#undef` lines as text, so that naga's
+    /// GLSL frontend handles them. GLSL macros can have any value, or parameters. They still
+    /// change the shader defs for the `#if*` directives that follow, see
+    /// [`glsl_define`](Self::glsl_define).
+    pub(super) fn parse_glsl(source: &str) -> Result<Self, ProcessShaderError> {
+        Self::parse_with_defines(source, false)
+    }
+
+    fn parse_with_defines(source: &str, defines: bool) -> Result<Self, ProcessShaderError> {
+        let conditionals = &SHADER_IMPORT_PROCESSOR.conditionals;
+        let mut root = Vec::new();
+        let mut blocks = Vec::<OpenBlock>::new();
+        let mut declared_import_path = None;
+        let mut allow_multiple_imports = false;
+        for line in source.lines() {
+            // `lines` only yields slices of `source`, so this is the line's position in it.
+            let start = line.as_ptr() as usize - source.as_ptr() as usize;
+            let span = start..start + line.len();
+
+            let node = if let Some(cap) = conditionals.if_regex.captures(line) {
+                let condition = Condition::parse_if(cap.get(1).unwrap().as_str());
+                OpenBlock::open(&mut blocks, condition)?;
+                continue;
+            } else if let Some(cap) = conditionals.ifdef_regex.captures(line) {
+                let condition = Condition::Defined(cap.get(1).unwrap().as_str().to_string());
+                OpenBlock::open(&mut blocks, condition)?;
+                continue;
+            } else if let Some(cap) = conditionals.ifndef_regex.captures(line) {
+                let condition = Condition::NotDefined(cap.get(1).unwrap().as_str().to_string());
+                OpenBlock::open(&mut blocks, condition)?;
+                continue;
+            } else if let Some(cap) = conditionals.elif_regex.captures(line) {
+                let condition = Condition::parse_if(cap.get(1).unwrap().as_str());
+                OpenBlock::next_branch(&mut blocks, "elif", condition)?;
+                continue;
+            } else if let Some(cap) = conditionals.elifdef_regex.captures(line) {
+                let condition = Condition::Defined(cap.get(1).unwrap().as_str().to_string());
+                OpenBlock::next_branch(&mut blocks, "elifdef", condition)?;
+                continue;
+            } else if let Some(cap) = conditionals.elifndef_regex.captures(line) {
+                let condition = Condition::NotDefined(cap.get(1).unwrap().as_str().to_string());
+                OpenBlock::next_branch(&mut blocks, "elifndef", condition)?;
+                continue;
+            } else if conditionals.else_regex.is_match(line) {
+                OpenBlock::next_branch(&mut blocks, "else", Condition::Always)?;
+                continue;
+            } else if conditionals.endif_regex.is_match(line) {
+                let block = blocks.pop().ok_or(ProcessShaderError::TooManyEndIfs)?;
+                block.finish()
+            } else if let Some(cap) = conditionals.define_regex.captures(line) {
+                let name = cap.get(1).unwrap().as_str();
+                let value = cap.get(2).unwrap().as_str().trim();
+                if !defines {
+                    push_node(&mut root, &mut blocks, Node::Text(span));
+                    Node::Define(Self::glsl_define(name, value))
+                } else if value.is_empty() {
+                    Node::Define(ShaderDefVal::from(name))
+                } else {
+                    match ShaderDefVal::parse_value(name, value) {
+                        Some(def) => Node::Define(def),
+                        None => {
+                            Node::Invalid(ProcessShaderError::InvalidShaderDef(line.trim().into()))
+                        }
+                    }
+                }
+            } else if let Some(cap) = conditionals.undef_regex.captures(line) {
+                if !defines {
+                    push_node(&mut root, &mut blocks, Node::Text(span));
+                }
+                Node::Undef(cap.get(1).unwrap().as_str().to_string())
+            } else if SHADER_IMPORT_PROCESSOR
+                .allow_multiple_imports_regex
+                .is_match(line)
+            {
+                // Only affects how this shader is imported, see `ShaderProcessor::apply_import`.
+                allow_multiple_imports = true;
+                continue;
+            } else if let Some(cap) = SHADER_IMPORT_PROCESSOR
+                .define_import_path_regex
+                .captures(line)
+            {
+                declared_import_path
+                    .get_or_insert_with(|| cap.get(1).unwrap().as_str().to_string());
+                continue;
+            } else {
+                match SHADER_IMPORT_PROCESSOR.parse_import_directive(line) {
+                    Ok(Some(directive)) => Node::Import(directive),
+                    Err(err) => Node::Invalid(err),
+                    Ok(None) if line.contains("#{") => Node::Substitute(span),
+                    Ok(None) => {
+                        let nodes = blocks
+                            .last_mut()
+                            .map_or(&mut root, |block| &mut block.nodes);
+                        // Merge with the previous line if only a plain `\n` separates them.
+                        if let Some(Node::Text(text)) = nodes.last_mut() {
+                            if text.end + 1 == span.start {
+                                text.end = span.end;
+                                continue;
+                            }
+                        }
+                        Node::Text(span)
+                    }
+                }
+            };
+            push_node(&mut root, &mut blocks, node);
+        }
+
+        if !blocks.is_empty() {
+            return Err(ProcessShaderError::NotEnoughEndIfs);
+        }
+        Ok(Self {
+            nodes: root,
+            declared_import_path,
+            allow_multiple_imports,
+        })
+    }
+
+    /// The def a GLSL `#define name value` line sets: an int or uint if that's the value, and
+    /// otherwise just defined, since the value can be any token sequence, or a macro body.
+    fn glsl_define(name: &str, value: &str) -> ShaderDefVal {
+        match ShaderDefVal::parse_value(name, value) {
+            Some(def @ (ShaderDefVal::Int(..) | ShaderDefVal::UInt(..))) => def,
+            _ => ShaderDefVal::from(name),
+        }
+    }
+
+    pub(super) fn declared_import_path(&self) -> Option<&str> {
+        self.declared_import_path.as_deref()
+    }
+
+    pub(super) fn allows_multiple_imports(&self) -> bool {
+        self.allow_multiple_imports
+    }
+
+    /// Walks the tree for `shader_defs`, passing each reached segment of `source` (the source the
+    /// tree was parsed from) to `visit`. `#define` and `#undef` directives update `shader_defs`.
+    pub(super) fn evaluate<'a>(
+        &'a self,
+        source: &'a str,
+        shader_defs: &mut ShaderDefs,
+        visit: &mut dyn FnMut(Segment<'a>, &mut ShaderDefs) -> Result<(), ProcessShaderError>,
+    ) -> Result<(), ProcessShaderError> {
+        evaluate_nodes(&self.nodes, source, shader_defs, visit)
+    }
+
+    /// Adds the defs the tree consults in any branch to `referenced`.
+    pub(super) fn referenced_defs(
+        &self,
+        source: &str,
+        referenced: &mut BTreeMap<String, DefReferences>,
+    ) {
+        collect_references(&self.nodes, source, referenced);
+    }
+
+    /// All imports in the tree, whether or not they are in an active block.
+    pub(super) fn imports(&self) -> Vec<&ImportDirective> {
+        let mut imports = Vec::new();
+        collect_imports(&self.nodes, &mut imports);
+        imports
+    }
+}
+
+/// How a shader consults a def, see [`ShaderTree::referenced_defs`].
+#[derive(Debug, Clone, Default)]
+pub(super) struct DefReferences {
+    /// The comparisons of the def against integer literals in `#if` expressions.
+    pub comparisons: BTreeSet<(Comparison, i64)>,
+    /// Whether an `#if*` directive consults the def, as opposed to only `#{NAME}` substitutions.
+    pub conditional: bool,
+}
+
+/// Adds `node` to the innermost open block, or to `root` outside of any block.
+fn push_node(root: &mut Vec<Node>, blocks: &mut [OpenBlock], node: Node) {
+    blocks
+        .last_mut()
+        .map_or(root, |block| &mut block.nodes)
+        .push(node);
+}
+
+fn collect_references(
+    nodes: &[Node],
+    source: &str,
+    referenced: &mut BTreeMap<String, DefReferences>,
+) {
+    for node in nodes {
+        match node {
+            Node::Conditional(branches) => {
+                for branch in branches {
+                    match &branch.condition {
+                        Condition::If(expr) => {
+                            for (def, comparison) in expr.references() {
+                                let references = referenced.entry(def).or_default();
+                                references.conditional = true;
+                                references.comparisons.extend(comparison);
+                            }
+                        }
+                        Condition::Defined(name) | Condition::NotDefined(name) => {
+                            referenced.entry(name.clone()).or_default().conditional = true;
+                        }
+                        Condition::Always | Condition::Invalid(_) => {}
+                    }
+                    collect_references(&branch.nodes, source, referenced);
+                }
+            }
+            Node::Substitute(span) => {
+                let mut line = &source[span.clone()];
+                while let Some(start) = line.find("#{") {
+                    line = &line[start + 2..];
+                    let end = line
+                        .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+                        .unwrap_or(line.len());
+                    if line[end..].starts_with('}') && end > 0 {
+                        referenced.entry(line[..end].to_string()).or_default();
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn collect_imports<'a>(nodes: &'a [Node], imports: &mut Vec<&'a ImportDirective>) {
+    for node in nodes {
+        match node {
+            Node::Conditional(branches) => {
+                for branch in branches {
+                    collect_imports(&branch.nodes, imports);
+                }
+            }
+            Node::Import(directive) => imports.push(directive),
+            _ => {}
+        }
+    }
+}
+
+fn evaluate_nodes<'a>(
+    nodes: &'a [Node],
+    source: &'a str,
+    shader_defs: &mut ShaderDefs,
+    visit: &mut dyn FnMut(Segment<'a>, &mut ShaderDefs) -> Result<(), ProcessShaderError>,
+) -> Result<(), ProcessShaderError> {
+    for node in nodes {
+        match node {
+            Node::Text(span) => visit(Segment::Text(&source[span.clone()]), shader_defs)?,
+            Node::Substitute(span) => {
+                visit(Segment::Substitute(&source[span.clone()]), shader_defs)?
+            }
+            Node::Conditional(branches) => {
+                for branch in branches {
+                    if branch.condition.evaluate(shader_defs)? {
+                        evaluate_nodes(&branch.nodes, source, shader_defs, visit)?;
+                        break;
+                    }
+                }
+            }
+            Node::Define(def) => {
+                shader_defs.insert(def.name().to_string(), def.clone());
+            }
+            Node::Undef(name) => {
+                shader_defs.remove(name);
+            }
+            Node::Import(directive) => visit(Segment::Import(directive), shader_defs)?,
+            Node::Invalid(err) => return Err(err.clone()),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,103 @@
+use naga::valid::{Capabilities, ValidationFlags, Validator};
+use serde::{Deserialize, Serialize};
+
+/// How [`ProcessedShader::reflect_with_validation`](super::ProcessedShader::reflect_with_validation)
+/// validates naga IR.
+///
+/// The default runs every check and allows no optional capabilities. Use
+/// [`ValidationConfig::from_features`] to accept what a `Device` was created with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct ValidationConfig {
+    pub flags: ValidationFlags,
+    pub capabilities: Capabilities,
+}
+
+impl ValidationConfig {
+    /// Runs every check and allows the capabilities that `features` enable.
+    pub fn from_features(features: wgpu::Features) -> Self {
+        Self {
+            flags: ValidationFlags::default(),
+            capabilities: capabilities_from_features(features),
+        }
+    }
+
+    pub fn with_flags(mut self, flags: ValidationFlags) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    pub fn with_capabilities(mut self, capabilities: Capabilities) -> Self {
+        self.capabilities = capabilities;
+        self
+    }
+
+    /// Whether a module that passed validation with this config also passes with `other`: this
+    /// config runs at least the checks of `other`, and allows no capability `other` doesn't.
+    pub fn implies(&self, other: &ValidationConfig) -> bool {
+        self.flags.contains(other.flags) && other.capabilities.contains(self.capabilities)
+    }
+
+    pub(super) fn validator(&self) -> Validator {
+        Validator::new(self.flags, self.capabilities)
+    }
+}
+
+/// The naga capabilities that a device with `features` supports, the same way wgpu derives them
+/// when creating shader modules.
+///
+/// Naga has no capability for `MULTIVIEW`, the view index builtin is always accepted.
+pub fn capabilities_from_features(features: wgpu::Features) -> Capabilities {
+    let mut capabilities = Capabilities::empty();
+    capabilities.set(
+        Capabilities::PUSH_CONSTANT,
+        features.contains(wgpu::Features::PUSH_CONSTANTS),
+    );
+    capabilities.set(
+        Capabilities::FLOAT64,
+        features.contains(wgpu::Features::SHADER_FLOAT64),
+    );
+    capabilities.set(
+        Capabilities::PRIMITIVE_INDEX,
+        features.contains(wgpu::Features::SHADER_PRIMITIVE_INDEX),
+    );
+    capabilities
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{ProcessedShader, ShaderReflectError};
+    use super::*;
+
+    #[test]
+    fn modules_are_validated_with_the_configured_capabilities() {
+        let shader = ProcessedShader::Wgsl(
+            "struct Constants { scale: f32; };
+var<push_constant> constants: Constants;
+[[stage(fragment)]]
+fn main() -> [[location(0)]] vec4<f32> { return vec4<f32>(constants.scale); }"
+                .into(),
+        );
+        let strict = ValidationConfig::from_features(wgpu::Features::empty());
+        assert!(matches!(
+            shader.reflect_with_validation(&strict),
+            Err(ShaderReflectError::Validation(_))
+        ));
+        let push_constants = ValidationConfig::from_features(wgpu::Features::PUSH_CONSTANTS);
+        let reflection = shader.reflect_with_validation(&push_constants).unwrap();
+        assert_eq!(reflection.validation, push_constants);
+    }
+
+    #[test]
+    fn stricter_configs_are_not_implied() {
+        let strict = ValidationConfig::default();
+        let lenient = ValidationConfig::from_features(wgpu::Features::SHADER_FLOAT64)
+            .with_flags(ValidationFlags::empty());
+        assert!(strict.implies(&strict));
+        assert!(strict.implies(&lenient));
+        assert!(!lenient.implies(&strict));
+        assert!(!strict
+            .with_capabilities(Capabilities::FLOAT64)
+            .implies(&strict));
+        assert!(!strict.with_flags(ValidationFlags::empty()).implies(&strict));
+    }
+}